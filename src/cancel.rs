@@ -0,0 +1,78 @@
+//! Stopping early.
+//!
+//! If one stage of the pipeline fails, or the user hits Ctrl-C, there's no
+//! point in the others carrying on, and certainly no point in saving a
+//! half-built index. All the stages share a `Cancel` flag. A stage that fails
+//! sets it, and every stage checks it regularly and stops if it's set.
+
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::INTERRUPTED_EXIT_STATUS;
+
+/// A flag that tells every stage of the pipeline to stop. Cloning it is
+/// cheap, and all clones share the same flag.
+#[derive(Clone, Default)]
+pub struct Cancel {
+    flag: Arc<AtomicBool>,
+
+    /// The flag of the `Cancel` this one was made from by `child`, if any.
+    parent: Option<Arc<AtomicBool>>,
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    /// A new flag for one piece of the work, which is also set whenever
+    /// `self` is. Cancelling it doesn't cancel `self`, so one failed run can
+    /// stop without stopping the runs that come after it.
+    pub fn child(&self) -> Cancel {
+        Cancel { flag: Arc::new(AtomicBool::new(false)), parent: Some(self.flag.clone()) }
+    }
+
+    /// Tell everyone to stop.
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// True if the work has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+            || self.parent.as_ref().is_some_and(|parent| parent.load(Ordering::Relaxed))
+    }
+
+    /// Return an `Interrupted` error if the work has been cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Pass `result` through, first cancelling everything if it's an error.
+    pub fn on_error<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_err() {
+            self.cancel();
+        }
+        result
+    }
+
+    /// Cancel the work when the user hits Ctrl-C, so that the pipeline can
+    /// wind down and delete its temporary files. Hitting Ctrl-C a second time
+    /// exits immediately.
+    pub fn cancel_on_ctrl_c(&self) -> io::Result<()> {
+        let flag = self.flag.clone();
+        ctrlc::set_handler(move || {
+            if flag.swap(true, Ordering::Relaxed) {
+                process::exit(INTERRUPTED_EXIT_STATUS);
+            }
+            eprintln!("\ninterrupted, cleaning up (press Ctrl-C again to quit now)");
+        })
+        .map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,300 @@
+//! Checkpoints, for resuming an interrupted indexing run.
+//!
+//! Building a big index can take hours. The slow part is indexing the
+//! documents and merging the temporary files, and the temporary files are
+//! already on disk; all that's missing, to pick up where a crashed run left
+//! off, is a record of which files they are and what's in them. That's what a
+//! checkpoint is.
+//!
+//! Every time `FileMerge` takes on a new temporary file, it saves a checkpoint
+//! next to the index, in `NAME.checkpoint`: how many documents are safely
+//! stored in temporary files, and its stacks of segments.
+//! Each checkpoint replaces the last one atomically, and a temporary file
+//! isn't deleted until a checkpoint that doesn't mention it has been saved.
+//! Once the index is complete, the checkpoint is deleted.
+//!
+//! The file is plain text, one item per line:
+//!
+//! ```text
+//! fingertips checkpoint 1
+//! options mode=files fields=false split=none
+//! input corpus/a.txt
+//! input corpus/b.txt
+//! base 3
+//! documents 1234
+//! published 2 ./index.s4.dat
+//! segment 1 ./index.tmp00000001.dat
+//! segment 0 ./index.tmp00000003.dat
+//! ```
+//!
+//! The `base` line is there only when adding documents to an existing index.
+//! It gives the generation of the index being added to. Each `segment` line
+//! gives a temporary file and the level of the stack it was on; `published`
+//! lines are the same, but for segments of the index being added to. They're
+//! listed in document order, oldest first.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context, Error};
+use crate::publish::Manifest;
+
+/// The first line of every checkpoint file.
+const MAGIC: &str = "fingertips checkpoint 1";
+
+/// Everything needed to resume an indexing run.
+#[derive(Clone, Default)]
+pub struct Checkpoint {
+    /// The indexing options that affect the contents of the index. A run can
+    /// only be resumed with the same options.
+    pub options: String,
+
+    /// The files being indexed. A run can only be resumed with the same files.
+    pub inputs: Vec<String>,
+
+    /// When adding documents to an existing index, the generation of that
+    /// index. Its documents come first, ahead of the new ones.
+    pub base: Option<u64>,
+
+    /// How many documents, counting from document id 0, are stored in the
+    /// base index and the temporary files listed in `segments`.
+    pub documents: usize,
+
+    /// The segments in `FileMerge`'s stacks, oldest first, each with the
+    /// level of the stack it was on.
+    pub segments: Vec<(usize, PathBuf)>,
+
+    /// The files in `segments` that belong to the index being added to,
+    /// rather than being temporary files. They're never deleted here.
+    pub published: Vec<PathBuf>,
+}
+
+impl Checkpoint {
+    /// A checkpoint for a run that hasn't done anything yet. When adding to
+    /// an existing index, `base` is its manifest, and `documents` is how many
+    /// documents it has; otherwise they're `None` and 0. The existing index's
+    /// segments come first, and are published along with the new ones.
+    pub fn new(
+        options: String,
+        inputs: &[PathBuf],
+        base: Option<&Manifest>,
+        documents: usize,
+    ) -> Checkpoint {
+        let segments = base.map_or(vec![], |manifest| manifest.segments.clone());
+        Checkpoint {
+            options,
+            inputs: input_names(inputs),
+            base: base.map(|manifest| manifest.generation),
+            documents,
+            published: segments.iter().map(|(_, path)| path.clone()).collect(),
+            segments,
+        }
+    }
+
+    /// Load the checkpoint saved in `filename`, if there is one.
+    pub fn load(filename: &Path) -> io::Result<Option<Checkpoint>> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).in_file(filename),
+        };
+        let bad = |what: &str| -> io::Error {
+            Error::Corrupt {
+                path: filename.to_path_buf(),
+                position: None,
+                what: format!("bad checkpoint file: {what}"),
+            }
+            .into()
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose().in_file(filename)?.as_deref() != Some(MAGIC) {
+            return Err(bad("unrecognized format"));
+        }
+        let mut checkpoint = Checkpoint::default();
+        for line in lines {
+            let line = line.in_file(filename)?;
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad(&line))?;
+            match key {
+                "options" => checkpoint.options = value.to_string(),
+                "input" => checkpoint.inputs.push(value.to_string()),
+                "base" => checkpoint.base = Some(value.parse().map_err(|_| bad(&line))?),
+                "documents" => {
+                    checkpoint.documents = value.parse().map_err(|_| bad(&line))?;
+                }
+                "segment" | "published" => {
+                    let (level, path) = value.split_once(' ').ok_or_else(|| bad(&line))?;
+                    let level = level.parse().map_err(|_| bad(&line))?;
+                    if key == "published" {
+                        checkpoint.published.push(PathBuf::from(path));
+                    }
+                    checkpoint.segments.push((level, PathBuf::from(path)));
+                }
+                _ => return Err(bad(&line)),
+            }
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Save this checkpoint in `filename`, replacing any previous one.
+    ///
+    /// The new checkpoint is written to a separate file first, then renamed
+    /// into place, so a crash can't leave a half-written checkpoint behind.
+    pub fn save(&self, filename: &Path) -> io::Result<()> {
+        let new_filename = filename.with_extension("checkpoint-new");
+        self.write(&new_filename).in_file(&new_filename)?;
+        fs::rename(new_filename, filename).in_file(filename)
+    }
+
+    /// Write this checkpoint to the file `filename`, and sync it to disk.
+    fn write(&self, filename: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        writeln!(out, "{MAGIC}")?;
+        writeln!(out, "options {}", self.options)?;
+        for input in &self.inputs {
+            writeln!(out, "input {input}")?;
+        }
+        if let Some(base) = self.base {
+            writeln!(out, "base {base}")?;
+        }
+        writeln!(out, "documents {}", self.documents)?;
+        for (level, path) in &self.segments {
+            let key = if self.published.contains(path) { "published" } else { "segment" };
+            writeln!(out, "{key} {level} {}", path.display())?;
+        }
+        out.into_inner()?.sync_all()
+    }
+
+    /// Delete the checkpoint saved in `filename`, if any.
+    pub fn remove(filename: &Path) -> io::Result<()> {
+        match fs::remove_file(filename) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err).in_file(filename),
+            _ => Ok(()),
+        }
+    }
+
+    /// All the temporary files this checkpoint refers to.
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.segments
+            .iter()
+            .map(|(_, path)| path)
+            .filter(|path| !self.published.contains(path))
+    }
+
+    /// Check that a run with the given `options` and `inputs`, adding to the
+    /// index generation `base` if any, can pick up where this checkpoint left
+    /// off.
+    pub fn check_resumable(
+        &self,
+        options: &str,
+        inputs: &[PathBuf],
+        base: Option<u64>,
+    ) -> io::Result<()> {
+        let mismatch = |what: &str| -> io::Error {
+            let message = format!("can't resume: the {what} are different from the interrupted run's");
+            Error::Usage(message).into()
+        };
+        if self.options != options {
+            return Err(mismatch("indexing options"));
+        }
+        if self.inputs != input_names(inputs) {
+            return Err(mismatch("input files"));
+        }
+        if self.base != base {
+            return Err(Error::Usage(
+                "can't resume: the interrupted run wasn't adding to the same index".to_string(),
+            )
+            .into());
+        }
+        for (_, segment) in &self.segments {
+            if !segment.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("can't resume: segment file {} is missing", segment.display()),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The names of the input files, as recorded in a checkpoint.
+fn input_names(inputs: &[PathBuf]) -> Vec<String> {
+    inputs.iter().map(|p| p.display().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::test_dir;
+
+    #[test]
+    fn save_and_load() {
+        let dir = test_dir("checkpoint");
+        let filename = dir.join("index.checkpoint");
+        assert!(Checkpoint::load(&filename).unwrap().is_none());
+
+        let published = dir.join("index.s4.dat");
+        let segment = dir.join("index.tmp00000003.dat");
+        let checkpoint = Checkpoint {
+            options: "mode=files fields=false split=none".to_string(),
+            inputs: vec!["corpus/a.txt".to_string(), "corpus/b b.txt".to_string()],
+            base: Some(2),
+            documents: 1234,
+            segments: vec![(2, published.clone()), (0, segment.clone())],
+            published: vec![published.clone()],
+        };
+        checkpoint.save(&filename).unwrap();
+
+        let loaded = Checkpoint::load(&filename).unwrap().unwrap();
+        assert_eq!(loaded.options, checkpoint.options);
+        assert_eq!(loaded.inputs, checkpoint.inputs);
+        assert_eq!(loaded.base, Some(2));
+        assert_eq!(loaded.documents, 1234);
+        assert_eq!(loaded.segments, checkpoint.segments);
+        assert_eq!(loaded.published, [published]);
+        // Only the temporary file is the checkpoint's to delete.
+        assert_eq!(loaded.files().collect::<Vec<_>>(), [&segment]);
+
+        Checkpoint::remove(&filename).unwrap();
+        assert!(Checkpoint::load(&filename).unwrap().is_none());
+        Checkpoint::remove(&filename).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_garbage() {
+        let dir = test_dir("checkpoint-garbage");
+        let filename = dir.join("index.checkpoint");
+        for text in ["not a checkpoint\n", &format!("{MAGIC}\ndocuments many\n"), &format!("{MAGIC}\nbogus 1\n")] {
+            fs::write(&filename, text).unwrap();
+            let err = Checkpoint::load(&filename).err().unwrap();
+            assert!(matches!(Error::from(err), Error::Corrupt { .. }));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_resumable() {
+        let dir = test_dir("checkpoint-resumable");
+        let inputs = [PathBuf::from("a.txt"), PathBuf::from("b.txt")];
+        let mut checkpoint = Checkpoint::new("mode=files".to_string(), &inputs, None, 0);
+        assert!(checkpoint.check_resumable("mode=files", &inputs, None).is_ok());
+
+        let is_usage = |result: io::Result<()>| matches!(result.map_err(Error::from), Err(Error::Usage(_)));
+        assert!(is_usage(checkpoint.check_resumable("mode=lines", &inputs, None)));
+        assert!(is_usage(checkpoint.check_resumable("mode=files", &inputs[..1], None)));
+        assert!(is_usage(checkpoint.check_resumable("mode=files", &inputs, Some(1))));
+
+        // Every segment has to still be there.
+        let segment = dir.join("index.tmp00000001.dat");
+        checkpoint.segments.push((0, segment.clone()));
+        let err = checkpoint.check_resumable("mode=files", &inputs, None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::write(&segment, b"").unwrap();
+        assert!(checkpoint.check_resumable("mode=files", &inputs, None).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
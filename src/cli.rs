@@ -0,0 +1,655 @@
+//! The command line. The first argument is a command, like `index` or
+//! `search`, and each command has a function of its own, like
+//! `index_command`, that parses the rest of the arguments and then calls on
+//! the rest of the crate to do the work.
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use argparse::{ArgumentParser, StoreTrue, Store, StoreOption, Collect, List};
+use regex::Regex;
+
+use crate::{expand_filename_arguments, run, IndexOptions, RunOptions};
+use crate::merge::{InputPolicy, MergeOptions, DEFAULT_FAN_IN, DEFAULT_MERGE_THREADS};
+use crate::source::InputMode;
+use crate::split::Splitter;
+use crate::memory::{parse_size, DEFAULT_MEMORY_LIMIT};
+use crate::progress::ProgressMode;
+use crate::cancel::Cancel;
+use crate::paths::{IndexPaths, DEFAULT_INDEX_NAME};
+use crate::publish::Manifest;
+use crate::space::SpaceCheck;
+use crate::search::print_matches;
+use crate::lock::WriteLock;
+use crate::update::{run_delete, run_update, run_watch};
+use crate::error::{Context, Error};
+use crate::inspect::{self, DumpFormat};
+
+/// Build the `Splitter` requested by the `--split-lines` and `--record-start`
+/// command-line options, if any.
+fn make_splitter(
+    split_lines: bool,
+    record_start: Option<String>,
+) -> Result<Option<Splitter>, Error> {
+    match (split_lines, record_start) {
+        (true, Some(_)) => Err(Error::Usage(
+            "--split-lines and --record-start can't be used together".to_string(),
+        )),
+        (true, None) => Ok(Some(Splitter::Lines)),
+        (false, Some(pattern)) => match Regex::new(&pattern) {
+            Ok(re) => Ok(Some(Splitter::Records(re))),
+            Err(err) => Err(Error::Usage(format!("bad --record-start pattern: {err}"))),
+        },
+        (false, None) => Ok(None),
+    }
+}
+
+/// The commands `fingertips` understands, named by the first argument.
+#[derive(Clone, Copy)]
+enum Command {
+    Index,
+    Update,
+    Watch,
+    Delete,
+    Search,
+    Stats,
+    Dump,
+    Verify,
+    Merge,
+}
+
+impl FromStr for Command {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Command, ()> {
+        match s {
+            "index" => Ok(Command::Index),
+            "update" => Ok(Command::Update),
+            "watch" => Ok(Command::Watch),
+            "delete" => Ok(Command::Delete),
+            "search" => Ok(Command::Search),
+            "stats" => Ok(Command::Stats),
+            "dump" => Ok(Command::Dump),
+            "verify" => Ok(Command::Verify),
+            "merge" => Ok(Command::Merge),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parse the arguments of a command, or print a usage message and exit if
+/// they're bad (or if they ask for `--help`).
+fn parse_command_args(ap: ArgumentParser, args: Vec<String>) {
+    if let Err(status) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+        process::exit(status);
+    }
+}
+
+/// The command-line options that say where the index is. Every command takes
+/// these.
+struct IndexLocation {
+    dir: PathBuf,
+    name: String,
+    tmp_dir: Option<PathBuf>,
+}
+
+impl IndexLocation {
+    fn new() -> IndexLocation {
+        IndexLocation { dir: PathBuf::from("."), name: DEFAULT_INDEX_NAME.to_string(), tmp_dir: None }
+    }
+
+    /// Add the options to `ap`. Only commands that write temporary files take
+    /// `--tmp-dir`.
+    fn add_options<'p>(&'p mut self, ap: &mut ArgumentParser<'p>, with_tmp_dir: bool) {
+        ap.refer(&mut self.dir)
+            .add_option(
+                &["-o", "--output", "-d", "--dir"],
+                Store,
+                "Directory where the index is saved. It's created if it \
+                 doesn't exist. The default is the current directory.",
+            )
+            .metavar("DIR");
+        ap.refer(&mut self.name)
+            .add_option(
+                &["--index-name"],
+                Store,
+                "Name of the index. NAME.current names the files that \
+                 make up the latest version of it, like NAME.1.manifest \
+                 and NAME.1.docs. The default is \"index\".",
+            )
+            .metavar("NAME");
+        if with_tmp_dir {
+            ap.refer(&mut self.tmp_dir)
+                .add_option(
+                    &["--tmp-dir"],
+                    StoreOption,
+                    "Directory for temporary files, which can be on a \
+                     different filesystem from the index. The default is \
+                     the index directory.",
+                )
+                .metavar("DIR");
+        }
+    }
+
+    fn paths(self) -> Result<IndexPaths, Error> {
+        if self.name.is_empty() || self.name.contains(std::path::is_separator) {
+            return Err(Error::Usage("--index-name must be a filename, not a path".to_string()));
+        }
+        let tmp_dir = self.tmp_dir.unwrap_or_else(|| self.dir.clone());
+        Ok(IndexPaths::new(self.dir, self.name, tmp_dir))
+    }
+}
+
+/// The command-line options that control how documents are read and indexed,
+/// for commands that index documents.
+struct DocumentArgs {
+    indexing_threads: usize,
+    use_fields: bool,
+    mail: bool,
+    split_lines: bool,
+    record_start: Option<String>,
+    memory_limit: Option<String>,
+}
+
+impl DocumentArgs {
+    fn new() -> DocumentArgs {
+        DocumentArgs {
+            indexing_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            use_fields: false,
+            mail: false,
+            split_lines: false,
+            record_start: None,
+            memory_limit: None,
+        }
+    }
+
+    fn add_options<'p>(&'p mut self, ap: &mut ArgumentParser<'p>) {
+        ap.refer(&mut self.indexing_threads)
+            .add_option(
+                &["-j", "--indexing-threads"],
+                Store,
+                "Number of threads to use for tokenizing and indexing \
+                 documents. The default is the number of CPUs.",
+            )
+            .metavar("N");
+        ap.refer(&mut self.memory_limit)
+            .add_option(
+                &["--memory-limit"],
+                StoreOption,
+                "Approximate amount of memory to use for in-memory indexes \
+                 before writing them to temporary files, like 512M or 4G. \
+                 The default is 1G.",
+            )
+            .metavar("SIZE");
+        ap.refer(&mut self.use_fields)
+            .add_option(
+                &["--fields"],
+                StoreTrue,
+                "Index HTML titles and HTML and Markdown headings as \
+                 separate fields, searchable as title:WORD and heading:WORD.",
+            );
+        ap.refer(&mut self.mail)
+            .add_option(
+                &["--mail"],
+                StoreTrue,
+                "Index email: each file is an mbox mailbox and each \
+                 directory a Maildir. Every message is a document, with \
+                 its From, To, Subject and Date headers indexed as fields.",
+            );
+        ap.refer(&mut self.split_lines)
+            .add_option(
+                &["--split-lines"],
+                StoreTrue,
+                "Index each line of each file as a separate document.",
+            );
+        ap.refer(&mut self.record_start)
+            .add_option(
+                &["--record-start"],
+                StoreOption,
+                "Split each file into records, each starting with a line \
+                 that matches this regular expression, and index each \
+                 record as a separate document.",
+            )
+            .metavar("REGEX");
+    }
+
+}
+
+/// The command-line options that control how a run goes, for every command
+/// that writes a new generation of the index by merging.
+struct RunArgs {
+    single_threaded: bool,
+    merge_fan_in: usize,
+    merge_threads: usize,
+    keep_merged: bool,
+    quiet: bool,
+    progress: Option<String>,
+    space_check: String,
+}
+
+impl RunArgs {
+    fn new() -> RunArgs {
+        RunArgs {
+            single_threaded: false,
+            merge_fan_in: DEFAULT_FAN_IN,
+            merge_threads: DEFAULT_MERGE_THREADS,
+            keep_merged: false,
+            quiet: false,
+            progress: None,
+            space_check: "fail".to_string(),
+        }
+    }
+
+    fn add_options<'p>(&'p mut self, ap: &mut ArgumentParser<'p>) {
+        ap.refer(&mut self.single_threaded)
+            .add_option(
+                &["-1", "--single-threaded"],
+                StoreTrue,
+                "Do all the work on a single thread.",
+            );
+        ap.refer(&mut self.merge_fan_in)
+            .add_option(
+                &["--merge-fan-in"],
+                Store,
+                "Maximum number of files to merge at once. The default \
+                 is 8.",
+            )
+            .metavar("N");
+        ap.refer(&mut self.merge_threads)
+            .add_option(
+                &["--merge-threads"],
+                Store,
+                "Number of merges to run at once, in the background while \
+                 indexing continues. 0 means merge in the foreground. The \
+                 default is 2.",
+            )
+            .metavar("N");
+        ap.refer(&mut self.keep_merged)
+            .add_option(
+                &["--keep-merged"],
+                StoreTrue,
+                "Don't delete temporary index files once they're merged. \
+                 They're left in the temporary directory, to be looked at \
+                 with `fingertips dump`.",
+            );
+        ap.refer(&mut self.quiet)
+            .add_option(
+                &["-q", "--quiet"],
+                StoreTrue,
+                "Don't report progress. Same as --progress=none.",
+            );
+        ap.refer(&mut self.progress)
+            .add_option(
+                &["--progress"],
+                StoreOption,
+                "How to report progress: none; bar, a progress bar on \
+                 stderr; or json, a stream of JSON events on stdout, one \
+                 per line. The default is bar if stderr is a terminal, \
+                 and none otherwise.",
+            )
+            .metavar("MODE");
+        ap.refer(&mut self.space_check)
+            .add_option(
+                &["--space-check"],
+                Store,
+                "What to do if there might not be enough disk space for \
+                 the index and its temporary files: fail, before starting; \
+                 warn, and carry on; or none, to skip the check. The \
+                 default is fail.",
+            )
+            .metavar("MODE");
+    }
+}
+
+/// Check the command-line options, and sort them into what goes into the
+/// index and how the run goes. `add`, `resume` and `compact` are for the
+/// command to decide.
+fn index_options(
+    documents: DocumentArgs,
+    run: RunArgs,
+    add: bool,
+    resume: bool,
+    compact: bool,
+) -> Result<(IndexOptions, RunOptions), Error> {
+    let progress_mode = match run.progress {
+        _ if run.quiet => ProgressMode::Quiet,
+        Some(mode) => ProgressMode::parse(&mode)?,
+        None if io::stderr().is_terminal() => ProgressMode::Bar,
+        None => ProgressMode::Quiet,
+    };
+    if run.merge_fan_in < 2 {
+        return Err(Error::Usage("--merge-fan-in must be at least 2".to_string()));
+    }
+    let options = IndexOptions {
+        mode: if documents.mail { InputMode::Mail } else { InputMode::Files },
+        use_fields: documents.use_fields,
+        splitter: make_splitter(documents.split_lines, documents.record_start)?,
+        indexing_threads: documents.indexing_threads,
+        memory_limit: match documents.memory_limit {
+            Some(size) => parse_size(&size)?,
+            None => DEFAULT_MEMORY_LIMIT,
+        },
+        merge: MergeOptions {
+            fan_in: run.merge_fan_in,
+            threads: run.merge_threads,
+            compact,
+            inputs: if run.keep_merged { InputPolicy::Keep } else { InputPolicy::Delete },
+        },
+    };
+    let run_options = RunOptions {
+        single_threaded: run.single_threaded,
+        add,
+        resume,
+        progress_mode,
+        space_check: SpaceCheck::parse(&run.space_check)?,
+    };
+    Ok((options, run_options))
+}
+
+/// Help text for the list of files and directories to index.
+const FILENAMES_HELP: &str = "\
+    Names of files/directories to index. \
+    For directories, all files in the directory \
+    and its subdirectories are indexed. Files inside \
+    .tar, .tar.gz, .tgz and .zip archives are \
+    indexed without unpacking them.";
+
+/// `fingertips index`: build an index, or add to one.
+fn index_command(args: Vec<String>) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut document_args = DocumentArgs::new();
+    let mut run_args = RunArgs::new();
+    let mut add = false;
+    let mut resume = false;
+    let mut compact = false;
+    let mut filenames = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Make an inverted index for searching documents.");
+        location.add_options(&mut ap, true);
+        document_args.add_options(&mut ap);
+        run_args.add_options(&mut ap);
+        ap.refer(&mut add)
+            .add_option(
+                &["--add"],
+                StoreTrue,
+                "Add the files to the existing index, instead of replacing \
+                 it.",
+            );
+        ap.refer(&mut resume)
+            .add_option(
+                &["--resume"],
+                StoreTrue,
+                "Pick up where an interrupted run left off, instead of \
+                 starting over. The files and indexing options must be \
+                 the same as before.",
+            );
+        ap.refer(&mut compact)
+            .add_option(
+                &["--compact"],
+                StoreTrue,
+                "Merge the whole index into a single segment, which makes \
+                 searching faster and reclaims the space used by deleted \
+                 documents.",
+            );
+        ap.refer(&mut filenames).add_argument("filenames", Collect, FILENAMES_HELP);
+        parse_command_args(ap, args);
+    }
+
+    let paths = location.paths()?;
+    let (options, run_options) = index_options(document_args, run_args, add, resume, compact)?;
+    fs::create_dir_all(paths.dir()).in_file(paths.dir())?;
+    let _lock = WriteLock::acquire(&paths)?;
+    let interrupt = Cancel::new();
+    interrupt.cancel_on_ctrl_c()?;
+    let documents = expand_filename_arguments(filenames, options.mode, &paths)?;
+    run(documents, paths, options, run_options, &interrupt)
+}
+
+/// `fingertips update` and `fingertips watch`, which take the same options.
+fn update_command(args: Vec<String>, watch: bool) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut document_args = DocumentArgs::new();
+    let mut run_args = RunArgs::new();
+    let mut resume = false;
+    let mut filenames = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        if watch {
+            ap.set_description(
+                "Index files and directories, or bring the existing index \
+                 up to date with them, then keep watching them and update \
+                 the index whenever they change, until interrupted with \
+                 Ctrl-C. Linux only.",
+            );
+        } else {
+            ap.set_description(
+                "Bring the existing index up to date with files and \
+                 directories: index files that are new or have changed \
+                 since they were indexed, and delete the documents from \
+                 files that are gone. Unchanged files aren't read again.",
+            );
+        }
+        location.add_options(&mut ap, true);
+        document_args.add_options(&mut ap);
+        run_args.add_options(&mut ap);
+        if !watch {
+            ap.refer(&mut resume)
+                .add_option(
+                    &["--resume"],
+                    StoreTrue,
+                    "Pick up where an interrupted update left off.",
+                );
+        }
+        ap.refer(&mut filenames)
+            .required()
+            .add_argument("filenames", Collect, FILENAMES_HELP);
+        parse_command_args(ap, args);
+    }
+
+    let paths = location.paths()?;
+    let (options, run_options) = index_options(document_args, run_args, false, resume, false)?;
+    // `watch` holds the lock the whole time it's watching, not just while
+    // it's updating, so nothing else changes the index behind its back.
+    let _lock = WriteLock::acquire(&paths)?;
+    let interrupt = Cancel::new();
+    interrupt.cancel_on_ctrl_c()?;
+    if watch {
+        run_watch(filenames, paths, options, run_options, &interrupt)
+    } else {
+        run_update(filenames, paths, options, run_options, &interrupt)
+    }
+}
+
+/// `fingertips delete`: delete documents from an index.
+fn delete_command(args: Vec<String>) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut filenames = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Delete the documents that came from the named files from the \
+             index. An archive, mbox file or Maildir stands for all the \
+             documents in it. The files must be named the same way as when \
+             they were indexed.",
+        );
+        location.add_options(&mut ap, false);
+        ap.refer(&mut filenames)
+            .required()
+            .add_argument("filenames", Collect, "Files whose documents to delete.");
+        parse_command_args(ap, args);
+    }
+    let paths = location.paths()?;
+    let _lock = WriteLock::acquire(&paths)?;
+    run_delete(filenames, &paths)
+}
+
+/// `fingertips search`: look up a word.
+fn search_command(args: Vec<String>) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut word = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Search the index for a word, and print the documents that \
+             contain it, with the number of times it appears in each.",
+        );
+        location.add_options(&mut ap, false);
+        ap.refer(&mut word).required().add_argument(
+            "word",
+            Store,
+            "The word to search for. To search a field, like the titles \
+             of web pages or the subjects of email, put the field name \
+             first: title:WORD. The fields are title, heading, from, to, \
+             subject and date.",
+        );
+        parse_command_args(ap, args);
+    }
+    print_matches(&location.paths()?, &word)
+}
+
+/// `fingertips stats` and `fingertips verify`, which take only the location
+/// of the index.
+fn inspect_command(args: Vec<String>, command: Command) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(match command {
+            Command::Verify => {
+                "Check the index for damage, reading all of it. Problems are \
+                 printed as they're found."
+            }
+            _ => "Print the number of documents in the index, and the size \
+                  and level of each of its segments.",
+        });
+        location.add_options(&mut ap, false);
+        parse_command_args(ap, args);
+    }
+    let paths = location.paths()?;
+    match command {
+        Command::Verify => inspect::verify(&paths)?,
+        _ => inspect::print_stats(&paths)?,
+    }
+    Ok(())
+}
+
+/// `fingertips dump`: print what's in index files.
+fn dump_command(args: Vec<String>) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut terms: Vec<String> = vec![];
+    let mut json = false;
+    let mut filenames: Vec<PathBuf> = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Print what's in index files: the header, and the table of \
+             contents, with each term, the number of documents it appears \
+             in, and the offset and size of its data. The files are only \
+             read, never changed.",
+        );
+        location.add_options(&mut ap, false);
+        ap.refer(&mut terms)
+            .add_option(
+                &["-t", "--term"],
+                Collect,
+                "Also decode and print the hits for TERM: each document it \
+                 appears in, and where. Can be given more than once.",
+            )
+            .metavar("TERM");
+        ap.refer(&mut json)
+            .add_option(
+                &["--json"],
+                StoreTrue,
+                "Print one JSON object per line, instead of tab-separated \
+                 text.",
+            );
+        ap.refer(&mut filenames).add_argument(
+            "filenames",
+            Collect,
+            "Index files to dump. The default is every segment of the \
+             index.",
+        );
+        parse_command_args(ap, args);
+    }
+    if filenames.is_empty() {
+        let paths = location.paths()?;
+        let manifest = Manifest::load_existing(&paths)?;
+        filenames = manifest.segments.into_iter().map(|(_, path)| path).collect();
+    }
+    let terms: Vec<String> = terms.iter().map(|term| term.to_lowercase()).collect();
+    let format = if json { DumpFormat::Json } else { DumpFormat::Text };
+    for filename in filenames {
+        inspect::dump(&filename, &terms, format)?;
+    }
+    Ok(())
+}
+
+/// `fingertips merge`: merge all the segments of an index into one.
+fn merge_command(args: Vec<String>) -> Result<(), Error> {
+    let mut location = IndexLocation::new();
+    let mut run_args = RunArgs::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Merge all the segments of the index into one, which makes \
+             searching faster and reclaims the space used by deleted \
+             documents.",
+        );
+        location.add_options(&mut ap, true);
+        run_args.add_options(&mut ap);
+        parse_command_args(ap, args);
+    }
+    let paths = location.paths()?;
+    let (options, run_options) = index_options(DocumentArgs::new(), run_args, true, false, true)?;
+    let _lock = WriteLock::acquire(&paths)?;
+    let interrupt = Cancel::new();
+    interrupt.cancel_on_ctrl_c()?;
+    run(vec![], paths, options, run_options, &interrupt)
+}
+
+/// Parse the command line, and run the command it names. Bad arguments, and
+/// `--help`, print a message and exit without returning.
+pub fn run_command() -> Result<(), Error> {
+    let mut command = Command::Index;
+    let mut args = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Make and search inverted indexes of documents. Run \
+             `fingertips COMMAND --help` for help with each command.",
+        );
+        ap.refer(&mut command)
+            .required()
+            .add_argument(
+                "command",
+                Store,
+                "What to do: index, to build an index or add to one; \
+                 update, to reindex files that changed; watch, to keep \
+                 updating as files change; delete, to delete documents; \
+                 search, to look up a word; stats, to summarize the index; \
+                 dump, to print index files; verify, to check the index \
+                 for damage; or merge, to merge its segments into one.",
+            );
+        ap.refer(&mut args).add_argument("arguments", List, "Arguments for the command.");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+
+    // The command's own parser expects the program name first.
+    let program = env::args().next().unwrap_or_else(|| "fingertips".to_string());
+    args.insert(0, format!("{program} {}", env::args().nth(1).unwrap_or_default()));
+    match command {
+        Command::Index => index_command(args),
+        Command::Update => update_command(args, false),
+        Command::Watch => update_command(args, true),
+        Command::Delete => delete_command(args),
+        Command::Search => search_command(args),
+        Command::Stats | Command::Verify => inspect_command(args, command),
+        Command::Dump => dump_command(args),
+        Command::Merge => merge_command(args),
+    }
+}
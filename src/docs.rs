@@ -0,0 +1,308 @@
+//! The document table.
+//!
+//! An index file refers to documents only by number. The document table,
+//! saved next to the index, is what turns those numbers back into names a
+//! person can use: for each document id, in order, the name of the document,
+//! and where in that file the document is, if it's only part of a file. It
+//! also keeps track of which documents have been deleted (see `tombstones`),
+//! and what the file each document came from looked like when it was indexed
+//! (see `stamp`).
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{Context, Error, Position};
+use crate::mail;
+use crate::stamp::{FileStamp, PendingStamp};
+use crate::tombstones::Tombstones;
+
+/// How a missing stamp is saved. No real file's stamp is all zeros, since the
+/// hash of even an empty file isn't zero.
+const NO_STAMP: FileStamp = FileStamp { mtime: 0, size: 0, hash: 0 };
+
+/// Size of the fixed part of an entry in a document table file: the length of
+/// the name, the location, and the stamp.
+const ENTRY_HEADER_SIZE: u64 = 4 + 6 * 8;
+
+/// Where a document is within a file, for files that were split into many
+/// documents.
+#[derive(Clone, Copy)]
+pub struct Location {
+    /// The line number where the document starts, counting from 1.
+    pub line: u64,
+
+    /// Byte offset of the start of the document within the file.
+    pub start: u64,
+
+    /// Byte offset of the end of the document within the file.
+    pub end: u64,
+}
+
+/// What the document table knows about one document.
+struct DocumentInfo {
+    name: String,
+    location: Option<Location>,
+    stamp: Option<PendingStamp>,
+}
+
+/// The names of all documents in an index, indexed by document id.
+#[derive(Default)]
+pub struct DocumentTable {
+    docs: Vec<DocumentInfo>,
+    deleted: Tombstones,
+}
+
+impl DocumentTable {
+    /// Create a new, empty table.
+    pub fn new() -> DocumentTable {
+        DocumentTable::default()
+    }
+
+    /// Add a document to the table, returning its document id. The stamp's
+    /// hash can be filled in later, as long as it's before the table is
+    /// written.
+    pub fn push(&mut self, name: String, location: Option<Location>, stamp: Option<PendingStamp>) -> usize {
+        self.docs.push(DocumentInfo { name, location, stamp });
+        self.docs.len() - 1
+    }
+
+    /// The number of documents in the table.
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    /// Mark document `doc_id` as deleted.
+    pub fn delete(&mut self, doc_id: usize) {
+        self.deleted.delete(doc_id);
+    }
+
+    /// True if document `doc_id` has been deleted.
+    pub fn is_deleted(&self, doc_id: usize) -> bool {
+        self.deleted.is_deleted(doc_id)
+    }
+
+    /// The set of deleted documents.
+    pub fn deleted(&self) -> &Tombstones {
+        &self.deleted
+    }
+
+    /// The name of document `doc_id`.
+    pub fn name(&self, doc_id: usize) -> &str {
+        &self.docs[doc_id].name
+    }
+
+    /// Where document `doc_id` is within its file, if it's only part of one.
+    pub fn location(&self, doc_id: usize) -> Option<Location> {
+        self.docs[doc_id].location
+    }
+
+    /// The stamp of the file document `doc_id` came from, if any.
+    pub fn stamp(&self, doc_id: usize) -> Option<FileStamp> {
+        self.docs[doc_id].stamp.as_ref().and_then(PendingStamp::get)
+    }
+
+    /// Replace the stamp of document `doc_id`, for a file that's been touched
+    /// but not changed.
+    pub fn set_stamp(&mut self, doc_id: usize, stamp: FileStamp) {
+        self.docs[doc_id].stamp = Some(stamp.into());
+    }
+
+    /// The ids of the documents that came from any of the files named
+    /// `paths`, in order: the files themselves, the members of an archive or
+    /// messages of an mbox file by one of those names, or the messages of a
+    /// Maildir by one of those names. The paths must be spelled the same way
+    /// as when they were indexed. This is a single pass over the table, so
+    /// it's fast even for many paths.
+    ///
+    /// A directory of ordinary files doesn't name the files in it; each one
+    /// has to be named itself. A path names a Maildir only if it's one on
+    /// disk, by the same test as when indexing (see `mail::is_maildir`), so a
+    /// directory that happens to have a `new` subdirectory isn't mistaken for
+    /// one.
+    pub fn ids_from_any(&self, paths: &[String]) -> Vec<usize> {
+        let maildirs: HashSet<&str> = paths
+            .iter()
+            .map(String::as_str)
+            .filter(|path| mail::is_maildir(Path::new(path)))
+            .collect();
+        let paths: HashSet<&str> = paths.iter().map(String::as_str).collect();
+        self.docs
+            .iter()
+            .enumerate()
+            .filter(|(_, doc)| {
+                let name = doc.name.as_str();
+                let archive = name.split_once("!/").map(|(archive, _)| archive);
+                paths.contains(name)
+                    || archive.is_some_and(|archive| paths.contains(archive))
+                    || maildir_of(name).is_some_and(|maildir| maildirs.contains(maildir))
+            })
+            .map(|(doc_id, _)| doc_id)
+            .collect()
+    }
+
+    /// Load a table saved by `write`.
+    pub fn read(filename: &Path, deleted_filename: &Path) -> io::Result<DocumentTable> {
+        let file = File::open(filename).in_file(filename)?;
+        let len = file.metadata().in_file(filename)?.len();
+        let mut input = BufReader::new(file);
+        let mut table = DocumentTable::new();
+        table.deleted = Tombstones::read(deleted_filename)?;
+        let mut offset = 0;
+        while !input.fill_buf().in_file(filename)?.is_empty() {
+            let position = Position::at(offset);
+            let (name, location, stamp) = read_entry(&mut input, filename, position, len - offset)
+                .in_file_at(filename, position)?;
+            offset += ENTRY_HEADER_SIZE + name.len() as u64;
+            let name = String::from_utf8(name).map_err(|_| Error::Corrupt {
+                path: filename.to_path_buf(),
+                position: Some(position),
+                what: "document name isn't valid UTF-8".to_string(),
+            })?;
+            let location = if location.line == 0 { None } else { Some(location) };
+            let stamp = if stamp == NO_STAMP { None } else { Some(stamp.into()) };
+            table.push(name, location, stamp);
+        }
+        Ok(table)
+    }
+
+    /// Save the table to the file `filename`, and the set of deleted documents
+    /// to `deleted_filename`, and sync them to disk.
+    ///
+    /// The file is simply a sequence of entries, in document id order. Each
+    /// entry is the name, stored as a little-endian u32 byte count followed by
+    /// that many bytes of UTF-8; then the location, as three little-endian
+    /// u64 values: line, start, and end; then the stamp, as three more:
+    /// modification time, size, and hash. Documents that are whole files have
+    /// a location of all zeros, and documents with no stamp have a stamp of
+    /// all zeros.
+    pub fn write(&self, filename: &Path, deleted_filename: &Path) -> io::Result<()> {
+        self.deleted.write(deleted_filename)?;
+        self.write_docs(filename).in_file(filename)
+    }
+
+    fn write_docs(&self, filename: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        for doc in &self.docs {
+            out.write_u32::<LittleEndian>(doc.name.len() as u32)?;
+            out.write_all(doc.name.as_bytes())?;
+            let location = doc.location.unwrap_or(Location { line: 0, start: 0, end: 0 });
+            out.write_u64::<LittleEndian>(location.line)?;
+            out.write_u64::<LittleEndian>(location.start)?;
+            out.write_u64::<LittleEndian>(location.end)?;
+            let stamp = doc.stamp.as_ref().and_then(PendingStamp::get).unwrap_or(NO_STAMP);
+            out.write_u64::<LittleEndian>(stamp.mtime)?;
+            out.write_u64::<LittleEndian>(stamp.size)?;
+            out.write_u64::<LittleEndian>(stamp.hash)?;
+        }
+        out.into_inner()?.sync_all()
+    }
+}
+
+/// The Maildir a document named `name` would be in, if it were a message
+/// named `MAILDIR/cur/MESSAGE` or `MAILDIR/new/MESSAGE`.
+fn maildir_of(name: &str) -> Option<&str> {
+    name.rsplit_once('/')
+        .and_then(|(dir, _)| dir.rsplit_once('/'))
+        .filter(|(_, subdir)| *subdir == "cur" || *subdir == "new")
+        .map(|(maildir, _)| maildir)
+}
+
+/// Read one entry of a document table file: the name, as bytes, the location,
+/// and the stamp. The entry is at `position` in the file `filename`, which has
+/// `remaining` bytes left from there; a name longer than that is damage, not a
+/// reason to allocate however much memory the length says.
+fn read_entry<R: Read>(
+    input: &mut R,
+    filename: &Path,
+    position: Position,
+    remaining: u64,
+) -> io::Result<(Vec<u8>, Location, FileStamp)> {
+    let len = input.read_u32::<LittleEndian>()? as u64;
+    if len > remaining.saturating_sub(ENTRY_HEADER_SIZE) {
+        return Err(Error::Corrupt {
+            path: filename.to_path_buf(),
+            position: Some(position),
+            what: format!("document name is {len} bytes long, but the file ends before that"),
+        }
+        .into());
+    }
+    let mut name = vec![0; len as usize];
+    input.read_exact(&mut name)?;
+    let location = Location {
+        line: input.read_u64::<LittleEndian>()?,
+        start: input.read_u64::<LittleEndian>()?,
+        end: input.read_u64::<LittleEndian>()?,
+    };
+    let stamp = FileStamp {
+        mtime: input.read_u64::<LittleEndian>()?,
+        size: input.read_u64::<LittleEndian>()?,
+        hash: input.read_u64::<LittleEndian>()?,
+    };
+    Ok((name, location, stamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tests::test_dir;
+
+    #[test]
+    fn ids_from_any_matches_files_archives_and_maildirs() {
+        let dir = test_dir("ids-from-any");
+        for subdir in ["Maildir/cur", "Maildir/new", "Maildir/tmp", "proj/new"] {
+            fs::create_dir_all(dir.join(subdir)).unwrap();
+        }
+        let path = |name: &str| dir.join(name).display().to_string();
+
+        let mut table = DocumentTable::new();
+        for name in [
+            "notes.txt",
+            "corpus.tar.gz!/inner/path.txt",
+            "mail.mbox!/1",
+            "Maildir/cur/1700000000.M1.host:2,S",
+            "Maildir/new/1700000001.M2.host",
+            "proj/index.html",
+            "proj/new/file.txt",
+            "proj/notes/readme.txt",
+        ] {
+            table.push(path(name), None, None);
+        }
+        let ids = |names: &[&str]| table.ids_from_any(&names.iter().map(|name| path(name)).collect::<Vec<_>>());
+
+        assert_eq!(ids(&["notes.txt"]), vec![0]);
+        assert_eq!(ids(&["corpus.tar.gz", "mail.mbox"]), vec![1, 2]);
+        assert_eq!(ids(&["Maildir"]), vec![3, 4]);
+        assert_eq!(ids(&["proj/index.html", "proj/new/file.txt"]), vec![5, 6]);
+
+        // A directory of ordinary files, even one with a `new` subdirectory,
+        // or a prefix of a name, doesn't match.
+        assert_eq!(ids(&["proj"]), Vec::<usize>::new());
+        assert_eq!(ids(&["corpus.tar.gz!/inner", "notes"]), Vec::<usize>::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_rejects_impossible_name_length() {
+        let dir = test_dir("docs-name-length");
+        let filename = dir.join("index.1.docs");
+        let deleted_filename = dir.join("index.1.del");
+        let mut table = DocumentTable::new();
+        table.push("a.txt".to_string(), None, None);
+        table.write(&filename, &deleted_filename).unwrap();
+        assert_eq!(DocumentTable::read(&filename, &deleted_filename).unwrap().name(0), "a.txt");
+
+        // A name length that runs past the end of the file is damage.
+        let mut data = fs::read(&filename).unwrap();
+        data[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&filename, data).unwrap();
+        let err = DocumentTable::read(&filename, &deleted_filename).err().unwrap();
+        assert!(matches!(Error::from(err), Error::Corrupt { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
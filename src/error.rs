@@ -0,0 +1,321 @@
+//! Errors, and the exit status each kind of error gets.
+//!
+//! Most of the code that does the actual work returns `io::Result`, since
+//! nearly everything that can go wrong is an I/O error, and the pipeline's
+//! threads pass them around as such. An `Error` that isn't an I/O error
+//! travels through that code wrapped in an `io::Error`, and is unwrapped again
+//! when it's converted back; see the `From` impls below. That way, a damaged
+//! index file found deep inside a merge still comes out of `main` as
+//! `Error::Corrupt`, with its own exit status.
+//!
+//! I/O errors from the operating system don't say which file they're about,
+//! and with thousands of files being read at once, that's the first thing
+//! anyone needs to know. The `Context` trait adds it, as close to where the
+//! error happens as possible.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Exit status for an I/O error, or any error not listed below.
+pub const IO_EXIT_STATUS: i32 = 1;
+
+/// Exit status for bad command-line arguments. This is also what `argparse`
+/// exits with.
+pub const USAGE_EXIT_STATUS: i32 = 2;
+
+/// Exit status when there's no index where one was expected.
+pub const NO_INDEX_EXIT_STATUS: i32 = 3;
+
+/// Exit status for a damaged index.
+pub const CORRUPT_EXIT_STATUS: i32 = 4;
+
+/// Exit status when there isn't enough disk space to build the index.
+pub const NO_SPACE_EXIT_STATUS: i32 = 5;
+
+/// Exit status when another program is already changing the index.
+pub const BUSY_EXIT_STATUS: i32 = 6;
+
+/// Exit status for a process killed by SIGINT, by shell convention.
+pub const INTERRUPTED_EXIT_STATUS: i32 = 130;
+
+/// Anything that can go wrong.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error.
+    Io(io::Error),
+
+    /// An I/O error while reading or writing the file `path`, at `position`,
+    /// if that's known.
+    File { path: PathBuf, position: Option<Position>, err: io::Error },
+
+    /// Bad command-line arguments, like an unknown option value, options that
+    /// can't be used together, or a search for something that isn't a word.
+    Usage(String),
+
+    /// There's no index in the directory `dir`.
+    NoIndex(PathBuf),
+
+    /// The index file `path` is damaged. `position` is where the damage was
+    /// found, if that's known.
+    Corrupt { path: PathBuf, position: Option<Position>, what: String },
+
+    /// `fingertips verify` found problems in the index. They've already been
+    /// printed.
+    Damaged { problems: usize },
+
+    /// There isn't enough disk space.
+    NoSpace(String),
+
+    /// Another program holds the lock file `path`, because it's changing the
+    /// index.
+    Busy(PathBuf),
+
+    /// The user hit Ctrl-C.
+    Interrupted,
+}
+
+/// The parts of an index file. (See `write::IndexFileWriter` for the format.)
+#[derive(Clone, Copy, Debug)]
+pub enum Section {
+    /// The first 16 bytes, which say what kind of file it is, and where the
+    /// table of contents starts.
+    Header,
+
+    /// The hits for each term, back to back.
+    Postings,
+
+    /// The table of contents.
+    Contents,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Section::Header => "header",
+            Section::Postings => "postings",
+            Section::Contents => "table of contents",
+        })
+    }
+}
+
+/// Where in a file something went wrong.
+#[derive(Clone, Copy, Debug)]
+pub struct Position {
+    /// Offset from the start of the file, in bytes.
+    pub offset: u64,
+
+    /// The part of the file, for index files.
+    pub section: Option<Section>,
+}
+
+impl Position {
+    /// The position `offset` bytes into a file.
+    pub fn at(offset: u64) -> Position {
+        Position { offset, section: None }
+    }
+
+    /// The position `offset` bytes into an index file, in `section`.
+    pub fn in_index(section: Section, offset: u64) -> Position {
+        Position { offset, section: Some(section) }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}", self.offset)?;
+        if let Some(section) = self.section {
+            write!(f, " ({section})")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    /// The exit status `fingertips` should exit with, after this error.
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            Error::Io(_) | Error::File { .. } => IO_EXIT_STATUS,
+            Error::Usage(_) => USAGE_EXIT_STATUS,
+            Error::NoIndex(_) => NO_INDEX_EXIT_STATUS,
+            Error::Corrupt { .. } | Error::Damaged { .. } => CORRUPT_EXIT_STATUS,
+            Error::NoSpace(_) => NO_SPACE_EXIT_STATUS,
+            Error::Busy(_) => BUSY_EXIT_STATUS,
+            Error::Interrupted => INTERRUPTED_EXIT_STATUS,
+        }
+    }
+
+    /// The closest `io::ErrorKind` to this error.
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(err) | Error::File { err, .. } => err.kind(),
+            Error::Usage(_) => io::ErrorKind::InvalidInput,
+            Error::NoIndex(_) => io::ErrorKind::NotFound,
+            Error::Corrupt { .. } | Error::Damaged { .. } => io::ErrorKind::InvalidData,
+            Error::NoSpace(_) => io::ErrorKind::StorageFull,
+            Error::Busy(_) => io::ErrorKind::WouldBlock,
+            Error::Interrupted => io::ErrorKind::Interrupted,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::File { path, position: None, err } => write!(f, "{}: {err}", path.display()),
+            Error::File { path, position: Some(position), err } => {
+                write!(f, "{}, at {position}: {err}", path.display())
+            }
+            Error::Usage(message) | Error::NoSpace(message) => write!(f, "{message}"),
+            Error::NoIndex(dir) => write!(f, "there's no index in {}", dir.display()),
+            Error::Corrupt { path, position: Some(position), what } => {
+                write!(f, "{}: damaged at {position}: {what}", path.display())
+            }
+            Error::Corrupt { path, position: None, what } => {
+                write!(f, "{}: damaged: {what}", path.display())
+            }
+            Error::Busy(path) => write!(
+                f,
+                "{}: another fingertips is already changing this index",
+                path.display()
+            ),
+            Error::Damaged { problems } => write!(f, "found {problems} problems in the index"),
+            Error::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) | Error::File { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().expect("checked above");
+            return *inner.downcast::<Error>().expect("checked above");
+        }
+        match err.kind() {
+            io::ErrorKind::Interrupted => Error::Interrupted,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+/// Adds the name of the file an I/O error is about, and where in it, to the
+/// error.
+///
+/// Errors that already say which file they're about, and errors that aren't
+/// about any file, like `Error::Interrupted`, are passed through unchanged.
+/// So it's safe to add context to a result that includes errors from other
+/// files: the innermost context wins.
+pub trait Context<T> {
+    /// Say that an error happened while reading or writing the file `path`.
+    fn in_file(self, path: &Path) -> io::Result<T>;
+
+    /// Say that an error happened at `position` in the file `path`, partway
+    /// through reading something. Running out of file there is reported as
+    /// damage, since the file is shorter than it says it is, and so is
+    /// `InvalidData`, data that doesn't make sense.
+    fn in_file_at(self, path: &Path, position: Position) -> io::Result<T>;
+
+    /// Say that an error happened `offset` bytes into the index file `path`,
+    /// in `section`.
+    fn in_index_file(self, path: &Path, section: Section, offset: u64) -> io::Result<T>
+    where
+        Self: Sized,
+    {
+        self.in_file_at(path, Position::in_index(section, offset))
+    }
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn in_file(self, path: &Path) -> io::Result<T> {
+        self.map_err(|err| with_context(err, path, None))
+    }
+
+    fn in_file_at(self, path: &Path, position: Position) -> io::Result<T> {
+        self.map_err(|err| with_context(err, path, Some(position)))
+    }
+}
+
+fn with_context(err: io::Error, path: &Path, position: Option<Position>) -> io::Error {
+    if err.get_ref().is_some_and(|inner| inner.is::<Error>())
+        || err.kind() == io::ErrorKind::Interrupted
+    {
+        return err;
+    }
+    let path = path.to_path_buf();
+    let err = match position {
+        Some(position) if err.kind() == io::ErrorKind::UnexpectedEof => Error::Corrupt {
+            path,
+            position: Some(position),
+            what: "the data ends too soon".to_string(),
+        },
+        Some(position) if err.kind() == io::ErrorKind::InvalidData => Error::Corrupt {
+            path,
+            position: Some(position),
+            what: err.to_string(),
+        },
+        _ => Error::File { path, position, err },
+    };
+    err.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_status_for_each_kind_of_error() {
+        let path = PathBuf::from("index.s1.dat");
+        let cases = [
+            (Error::Io(io::Error::other("oops")), IO_EXIT_STATUS),
+            (Error::File { path: path.clone(), position: None, err: io::Error::other("oops") }, IO_EXIT_STATUS),
+            (Error::Usage("bad option".to_string()), USAGE_EXIT_STATUS),
+            (Error::NoIndex(PathBuf::from(".")), NO_INDEX_EXIT_STATUS),
+            (
+                Error::Corrupt {
+                    path: path.clone(),
+                    position: Some(Position::in_index(Section::Header, 8)),
+                    what: "bad".to_string(),
+                },
+                CORRUPT_EXIT_STATUS,
+            ),
+            (Error::Damaged { problems: 3 }, CORRUPT_EXIT_STATUS),
+            (Error::NoSpace("full".to_string()), NO_SPACE_EXIT_STATUS),
+            (Error::Busy(PathBuf::from("index.lock")), BUSY_EXIT_STATUS),
+            (Error::Interrupted, INTERRUPTED_EXIT_STATUS),
+        ];
+        for (err, status) in cases {
+            assert_eq!(err.exit_status(), status, "{err}");
+        }
+    }
+
+    #[test]
+    fn errors_survive_a_trip_through_io_error() {
+        let err = io::Error::from(Error::Busy(PathBuf::from("index.lock")));
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(Error::from(err).exit_status(), BUSY_EXIT_STATUS);
+
+        let err = io::Error::from(Error::Io(io::Error::other("oops")));
+        assert_eq!(Error::from(err).exit_status(), IO_EXIT_STATUS);
+        let err = io::Error::from(io::ErrorKind::Interrupted);
+        assert_eq!(Error::from(err).exit_status(), INTERRUPTED_EXIT_STATUS);
+    }
+}
@@ -0,0 +1,453 @@
+//! Extracting plain text from marked-up documents.
+//!
+//! The tokenizer in `index` treats every run of alphanumeric characters as a
+//! word. That's fine for plain text, but for HTML it means tag names and
+//! attribute values like `div` and `class` end up in the index. So before
+//! indexing, we strip the markup out of HTML, XML, and Markdown documents.
+//!
+//! Optionally, text from an HTML `<title>` element is indexed as a separate
+//! `title` field, and text from headings as a `heading` field.
+
+use crate::source::{Body, Document, Field};
+
+/// Document formats we know how to extract text from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Plain,
+    Html,
+    Xml,
+    Markdown,
+}
+
+impl Format {
+    /// Figure out what kind of document this is, first by looking at its
+    /// filename extension, then by sniffing the first few bytes of the text.
+    fn detect(name: &str, text: &str) -> Format {
+        let basename = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        if let Some((_, ext)) = basename.rsplit_once('.') {
+            match ext.to_lowercase().as_str() {
+                "html" | "htm" | "xhtml" => return Format::Html,
+                "xml" => return Format::Xml,
+                "md" | "markdown" | "mdown" => return Format::Markdown,
+                "txt" => return Format::Plain,
+                _ => {}
+            }
+        }
+
+        let start: String = text.trim_start().chars().take(16).collect();
+        let start = start.to_lowercase();
+        if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            Format::Html
+        } else if start.starts_with("<?xml") {
+            Format::Xml
+        } else {
+            Format::Plain
+        }
+    }
+}
+
+/// Replace the text of `doc` with just the human-readable text in it,
+/// stripping out any markup.
+///
+/// If `use_fields` is true, titles and headings are also copied into separate
+/// fields of the document. Otherwise they're indexed only as part of the body.
+///
+/// Documents too big to load into memory are left alone, and indexed as
+/// plain text.
+pub fn extract_text(mut doc: Document, use_fields: bool) -> Document {
+    let Body::Text(text) = &doc.body else {
+        return doc;
+    };
+    let format = Format::detect(&doc.name, text);
+    let mut out = Extracted::new(use_fields);
+    match format {
+        Format::Plain => return doc,
+        Format::Html => strip_tags(text, true, &mut out),
+        Format::Xml => strip_tags(text, false, &mut out),
+        Format::Markdown => strip_markdown(text, &mut out),
+    }
+    let (body, fields) = out.finish();
+    doc.body = Body::Text(body);
+    doc.fields.extend(fields);
+    doc
+}
+
+/// Strip the markup from a piece of HTML, returning just the text.
+pub fn html_to_text(html: &str) -> String {
+    let mut out = Extracted::new(false);
+    strip_tags(html, true, &mut out);
+    out.finish().0
+}
+
+/// Where extracted text goes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Body,
+    Title,
+    Heading,
+}
+
+/// The text extracted from a document so far.
+struct Extracted {
+    use_fields: bool,
+    body: String,
+    title: String,
+    headings: String,
+}
+
+impl Extracted {
+    fn new(use_fields: bool) -> Extracted {
+        Extracted {
+            use_fields,
+            body: String::new(),
+            title: String::new(),
+            headings: String::new(),
+        }
+    }
+
+    /// Add some text. Titles aren't part of the body text of a document, but
+    /// headings are; so heading text goes both places.
+    fn push_str(&mut self, target: Target, s: &str) {
+        match target {
+            Target::Title if self.use_fields => self.title.push_str(s),
+            Target::Heading if self.use_fields => {
+                self.headings.push_str(s);
+                self.body.push_str(s);
+            }
+            _ => self.body.push_str(s),
+        }
+    }
+
+    /// Add a word break, so that words on either side of a tag or line
+    /// boundary don't run together.
+    fn push_break(&mut self, target: Target) {
+        self.push_str(target, " ");
+    }
+
+    /// Return the body text and any non-empty fields.
+    fn finish(self) -> (String, Vec<Field>) {
+        let mut fields = vec![];
+        if !self.title.trim().is_empty() {
+            fields.push(Field { name: "title", text: self.title });
+        }
+        if !self.headings.trim().is_empty() {
+            fields.push(Field { name: "heading", text: self.headings });
+        }
+        (self.body, fields)
+    }
+}
+
+/// HTML elements that don't break words, as in `<b>bold</b>face`.
+const INLINE_ELEMENTS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "dfn", "em", "i",
+    "kbd", "mark", "q", "s", "samp", "small", "span", "strong", "sub", "sup",
+    "time", "u", "var",
+];
+
+/// Strip tags, comments, and processing instructions from HTML or XML,
+/// leaving only the text.
+///
+/// This is not a real HTML parser. It doesn't need to be: it only has to find
+/// the text between the tags, and it's fine if it occasionally gets confused by
+/// badly broken markup.
+fn strip_tags(text: &str, html: bool, out: &mut Extracted) {
+    let mut target = Target::Body;
+    let mut rest = text;
+    while let Some(lt) = rest.find('<') {
+        push_decoded(&rest[..lt], target, out);
+        rest = &rest[lt..];
+
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = skip_past(after, "-->");
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>").unwrap_or(after.len());
+            out.push_str(target, &after[..end]);
+            rest = skip_past(after, "]]>");
+            continue;
+        }
+
+        let Some(tag_len) = tag_length(rest) else {
+            // An unterminated tag; ignore the rest of the document.
+            return;
+        };
+        let tag = &rest[1..tag_len - 1];
+        rest = &rest[tag_len..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        if !html {
+            out.push_break(target);
+            continue;
+        }
+
+        if !closing && (name == "script" || name == "style") {
+            // Skip everything up to the matching close tag.
+            let close = format!("</{name}");
+            let end = find_ignore_case(rest, &close).unwrap_or(rest.len());
+            rest = skip_past(&rest[end..], ">");
+            continue;
+        }
+
+        match (name.as_str(), closing) {
+            ("title", false) => target = Target::Title,
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => target = Target::Heading,
+            ("title" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => {
+                out.push_break(target);
+                target = Target::Body;
+            }
+            _ => {}
+        }
+        if !INLINE_ELEMENTS.contains(&name.as_str()) {
+            out.push_break(target);
+        }
+    }
+    push_decoded(rest, target, out);
+}
+
+/// The length in bytes of the tag at the start of `s`, including the angle
+/// brackets, or `None` if the tag is never closed. Quoted attribute values may
+/// contain `>`.
+fn tag_length(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '>') => return Some(i + 1),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Return the part of `s` after the first occurrence of `end`, or an empty
+/// string if `end` doesn't occur.
+fn skip_past<'a>(s: &'a str, end: &str) -> &'a str {
+    match s.find(end) {
+        Some(i) => &s[i + end.len()..],
+        None => "",
+    }
+}
+
+/// Like `str::find`, but ASCII case-insensitive.
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Append `text` to `out`, decoding character references like `&amp;` and
+/// `&#233;`. Unrecognized references are left alone.
+fn push_decoded(text: &str, target: Target, out: &mut Extracted) {
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(target, &rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').and_then(|semi| {
+            let c = decode_entity(&rest[1..semi])?;
+            Some((c, semi + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push_str(target, c.encode_utf8(&mut [0; 4]));
+                rest = &rest[len..];
+            }
+            None => {
+                out.push_str(target, "&");
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(target, rest);
+}
+
+/// Decode the name of a character reference, the part between `&` and `;`.
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(num) = name.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => None,
+    }
+}
+
+/// Strip Markdown syntax that would otherwise pollute the index: link and
+/// image URLs, reference definitions, and inline HTML. Heading lines go in the
+/// `heading` field.
+///
+/// Punctuation like `*` and `#` doesn't need to be removed, since the tokenizer
+/// ignores it anyway.
+fn strip_markdown(text: &str, out: &mut Extracted) {
+    let mut in_fence = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            out.push_str(Target::Body, line);
+            out.push_break(Target::Body);
+            continue;
+        }
+        if is_reference_definition(trimmed) {
+            continue;
+        }
+
+        let (target, content) = match trimmed.strip_prefix('#') {
+            Some(heading) => (Target::Heading, heading.trim_start_matches('#')),
+            None => (Target::Body, line),
+        };
+        strip_markdown_inline(content, target, out);
+        out.push_break(target);
+    }
+}
+
+/// True if `line` is a link reference definition, like `[id]: http://...`.
+fn is_reference_definition(line: &str) -> bool {
+    line.starts_with('[') && line.find("]:").is_some_and(|i| !line[..i].contains(']'))
+}
+
+/// Strip inline Markdown syntax from a single line: `[text](url)` becomes
+/// `text`, `![alt](url)` becomes `alt`, and autolinks and HTML tags are
+/// dropped.
+fn strip_markdown_inline(line: &str, target: Target, out: &mut Extracted) {
+    let mut rest = line;
+    while let Some(i) = rest.find(['[', '<']) {
+        let (before, at) = rest.split_at(i);
+        out.push_str(target, before.strip_suffix('!').unwrap_or(before));
+        if at.starts_with('<') {
+            match at.find('>') {
+                Some(end) => {
+                    out.push_break(target);
+                    rest = &at[end + 1..];
+                }
+                None => {
+                    out.push_str(target, at);
+                    rest = "";
+                }
+            }
+            continue;
+        }
+
+        // A link: `[text](url)` or `[text][ref]`. Keep the text, drop the rest.
+        match at.find(']') {
+            Some(close) => {
+                out.push_str(target, &at[1..close]);
+                let after = &at[close + 1..];
+                rest = if after.starts_with('(') {
+                    skip_past(after, ")")
+                } else if after.starts_with('[') {
+                    skip_past(after, "]")
+                } else {
+                    after
+                };
+            }
+            None => {
+                out.push_str(target, at);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(target, rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The whitespace-separated words of `text`, so that tests don't depend
+    /// on exactly where word breaks were added.
+    fn words(text: &str) -> Vec<&str> {
+        text.split_whitespace().collect()
+    }
+
+    /// Extract the text of a document, returning its body and fields.
+    fn extract(name: &str, text: &str, use_fields: bool) -> (String, Vec<(&'static str, String)>) {
+        let doc = extract_text(Document::new(name.to_string(), text.to_string()), use_fields);
+        let Body::Text(body) = doc.body else { unreachable!() };
+        let fields = doc.fields.into_iter().map(|f| (f.name, f.text.trim().to_string())).collect();
+        (body, fields)
+    }
+
+    #[test]
+    fn html_tags_scripts_and_entities() {
+        let html = "<p class=\"x > y\">Fish &amp; chips</p><!-- not <b>this</b> -->\
+                    <script type='text/javascript'>var hidden = 1;</SCRIPT>\
+                    <style>p { color: red }</style><b>bold</b>face<br>caf&#233; &#x2014; &bogus;";
+        assert_eq!(words(&html_to_text(html)), ["Fish", "&", "chips", "boldface", "café", "—", "&bogus;"]);
+        assert_eq!(html_to_text("no&nbsp;break"), "no\u{a0}break");
+    }
+
+    #[test]
+    fn xml_breaks_at_every_tag() {
+        let xml = "<?xml version=\"1.0\"?><doc><a>one</a><b>two</b><![CDATA[<three>]]></doc>";
+        let (body, fields) = extract("data.xml", xml, true);
+        assert_eq!(words(&body), ["one", "two", "<three>"]);
+        assert!(fields.is_empty());
+    }
+
+    #[test]
+    fn titles_and_headings() {
+        let html = "<html><head><title>The Title</title></head>\
+                    <body><h1>Heading</h1><p>Text</p></body></html>";
+
+        // Titles go only in their field; headings are part of the body too.
+        let (body, fields) = extract("page.htm", html, true);
+        assert_eq!(words(&body), ["Heading", "Text"]);
+        assert_eq!(fields, [("title", "The Title".to_string()), ("heading", "Heading".to_string())]);
+
+        // Without fields, it's all body text.
+        let (body, fields) = extract("page.htm", html, false);
+        assert_eq!(words(&body), ["The", "Title", "Heading", "Text"]);
+        assert!(fields.is_empty());
+    }
+
+    #[test]
+    fn format_detection() {
+        let html = "<!DOCTYPE html><p>hi</p>";
+        // Sniffed from the text, when the name doesn't say.
+        assert_eq!(words(&extract("page", html, false).0), ["hi"]);
+        // A `.txt` name wins over the contents.
+        assert_eq!(extract("page.txt", html, false).0, html);
+        // Plain text is left exactly as it was.
+        assert_eq!(extract("notes", "a <b> c", false).0, "a <b> c");
+    }
+
+    #[test]
+    fn markdown() {
+        let md = "# Intro\n\
+                  See [the docs](http://example.com/docs) and ![a cat](cat.png).\n\
+                  Inline <span>html</span> and [ref link][1].\n\
+                  [1]: http://example.com/ref\n\
+                  ```\n\
+                  # not a heading\n\
+                  ```\n";
+        let (body, fields) = extract("README.md", md, true);
+        assert_eq!(
+            words(&body),
+            ["Intro", "See", "the", "docs", "and", "a", "cat.", "Inline", "html", "and", "ref", "link.", "#", "not",
+             "a", "heading"],
+        );
+        assert_eq!(fields, [("heading", "Intro".to_string())]);
+    }
+}
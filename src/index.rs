@@ -5,7 +5,14 @@
 //! memory.
 
 use std::collections::HashMap;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, BufRead};
+use std::mem;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+/// The most text `from_reader` holds back at the end of a chunk, waiting to
+/// see whether a word carries on into the next one. No real word is this
+/// long; in a file that's all letters and digits, a "word" is cut off here.
+pub const MAX_HELD_BACK: usize = 64 * 1024;
 
 /// Break a string into words.
 fn tokenize(text: &str) -> Vec<&str> {
@@ -36,16 +43,42 @@ pub struct InMemoryIndex {
     /// might want to run on the index, so we preserve this property wherever
     /// possible.
     pub map: HashMap<String, Vec<Hit>>,
+
+    /// Approximate number of bytes of heap memory used by `map`.
+    heap_size: usize,
 }
 
+/// Approximate memory used by each distinct term in an `InMemoryIndex`, not
+/// counting the bytes of the term itself: the `String` and `Vec` headers, plus
+/// some slack for the hash table's own bookkeeping and unused slots.
+const TERM_OVERHEAD: usize = mem::size_of::<String>() + mem::size_of::<Vec<Hit>>() + 16;
+
+/// Approximate memory used by each `Hit`, not counting the hit data itself.
+const HIT_OVERHEAD: usize = mem::size_of::<Hit>();
+
 /// A `Hit` indicates that a particular document contains some term, how many
 /// times it appears, and at what offsets (that is, the word count, from the
 /// beginning of the document, of each place where the term appears).
 ///
 /// The buffer contains all the hit data in binary form, little-endian. The
-/// first u32 of the data is the document id. The remaining [u32] are offsets.
+/// first u32 of the data is the document id, and the second is the number of
+/// offsets that follow. The remaining [u32] are offsets. Since each hit says
+/// how long it is, hits can be stored back to back and still be told apart.
 pub type Hit = Vec<u8>;
 
+/// Size of the document id and count at the start of every `Hit`.
+pub const HIT_HEADER_SIZE: usize = 8;
+
+/// The document id of a hit.
+pub fn hit_doc_id(hit: &[u8]) -> usize {
+    LittleEndian::read_u32(&hit[0..4]) as usize
+}
+
+/// The offsets stored in a hit: where in the document the term appears.
+pub fn hit_offsets(hit: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    hit[HIT_HEADER_SIZE..].chunks_exact(4).map(LittleEndian::read_u32)
+}
+
 impl InMemoryIndex {
     /// Create a new, empty index.
     pub fn new() -> InMemoryIndex {
@@ -56,29 +89,121 @@ impl InMemoryIndex {
     ///
     /// The resulting index contains exactly one `Hit` per term.
     pub fn from_single_document(document_id: usize, text: String) -> InMemoryIndex {
-        let document_id = document_id as u32;
         let mut index = InMemoryIndex::new();
 
         let text = text.to_lowercase();
-        let tokens = tokenize(&text);
+        index.add_tokens(document_id as u32, "", &text, 0);
+        index
+    }
+
+    /// Index a single document, reading the text from `reader` a chunk at a
+    /// time rather than loading it all into memory.
+    ///
+    /// Words can straddle chunk boundaries, and so can the bytes of a single
+    /// UTF-8 character. So after each chunk, we hold back everything after the
+    /// last character that's definitely not part of a word, and tack it onto
+    /// the front of the next chunk. (At most `MAX_HELD_BACK` bytes, though.)
+    ///
+    /// The index of a big enough document doesn't fit in memory either. So
+    /// whenever the index reaches `part_size` bytes, it's handed to `flush`
+    /// and a new one started. The parts, in order, and then the index this
+    /// returns, together hold the document's hits; each part has at most one
+    /// hit per term, with offsets that carry on from the part before (see
+    /// `merge::join_parts`).
+    ///
+    /// Apart from being in parts, the result is the same as
+    /// `from_single_document` on the whole text. As with `fs::read_to_string`,
+    /// it's an error if the text isn't valid UTF-8.
+    pub fn from_reader<R: BufRead>(
+        document_id: usize,
+        mut reader: R,
+        part_size: usize,
+        mut flush: impl FnMut(InMemoryIndex) -> io::Result<()>,
+    ) -> io::Result<InMemoryIndex> {
+        let mut index = InMemoryIndex::new();
+        let mut pending: Vec<u8> = vec![];
+        let mut position = 0;
+        loop {
+            let chunk = reader.fill_buf()?;
+            let at_end = chunk.is_empty();
+            let chunk_len = chunk.len();
+            pending.extend_from_slice(chunk);
+            reader.consume(chunk_len);
+
+            // Find the part of `pending` that's complete UTF-8 text...
+            let valid = match std::str::from_utf8(&pending) {
+                Ok(text) => text,
+                Err(err) if err.error_len().is_none() && !at_end => {
+                    std::str::from_utf8(&pending[..err.valid_up_to()]).unwrap()
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    ));
+                }
+            };
+
+            // ...and of that, the part that ends before the last word, which
+            // might continue in the next chunk.
+            let end = match valid.rfind(|ch: char| !ch.is_alphanumeric()) {
+                _ if at_end => valid.len(),
+                Some(end) if valid.len() - end <= MAX_HELD_BACK => end,
+                None if valid.len() <= MAX_HELD_BACK => 0,
+                _ => valid.len(),
+            };
+
+            let text = valid[..end].to_lowercase();
+            position = index.add_tokens(document_id as u32, "", &text, position);
+            pending.drain(..end);
+            if at_end {
+                break;
+            }
+            if index.heap_size() >= part_size {
+                flush(mem::take(&mut index))?;
+            }
+        }
+        Ok(index)
+    }
+
+    /// Add a named field of a single document to an index created by
+    /// `from_single_document`.
+    ///
+    /// Each word in `text` is indexed as a separate term, `field:word`. Since
+    /// the tokenizer never produces words containing a colon, these can't be
+    /// confused with words from the body of the document. Each field name
+    /// should be added at most once per document, so that the index still
+    /// contains exactly one `Hit` per term.
+    pub fn add_field(&mut self, document_id: usize, field: &str, text: &str) {
+        let prefix = format!("{field}:");
+        self.add_tokens(document_id as u32, &prefix, &text.to_lowercase(), 0);
+    }
+
+    /// Index each word of `text` (which must already be lowercase) as a term,
+    /// with `prefix` tacked on the front. The first word is at offset
+    /// `first_position` in the document. Returns the offset of the next word.
+    fn add_tokens(&mut self, document_id: u32, prefix: &str, text: &str, first_position: usize) -> usize {
+        let tokens = tokenize(text);
         for (i, token) in tokens.iter().enumerate() {
+            let i = first_position + i;
             let hits =
-                index.map
-                .entry(token.to_string())
+                self.map
+                .entry(format!("{prefix}{token}"))
                 .or_insert_with(|| {
-                    let mut hits = Vec::with_capacity(4 + 4);
+                    self.heap_size += TERM_OVERHEAD + prefix.len() + token.len() + HIT_OVERHEAD + HIT_HEADER_SIZE;
+                    let mut hits = Vec::with_capacity(HIT_HEADER_SIZE + 4);
                     hits.write_u32::<LittleEndian>(document_id).unwrap();
+                    hits.write_u32::<LittleEndian>(0).unwrap();
                     vec![hits]
                 });
-            hits[0].write_u32::<LittleEndian>(i as u32).unwrap();
-            index.word_count += 1;
-        }
-
-        if document_id % 100 == 0 {
-            println!("indexed document {document_id}, {} bytes, {} words", text.len(), index.word_count);
+            let hit = &mut hits[0];
+            let count = LittleEndian::read_u32(&hit[4..8]);
+            LittleEndian::write_u32(&mut hit[4..8], count + 1);
+            hit.write_u32::<LittleEndian>(i as u32).unwrap();
+            self.heap_size += 4;
+            self.word_count += 1;
         }
-
-        index
+        first_position + tokens.len()
     }
 
     /// Add all search hits from `other` to this index.
@@ -87,10 +212,15 @@ impl InMemoryIndex {
     /// ids in `other` are greater than every document id in `*self`, then
     /// `*self` remains sorted by document id after merging.
     pub fn merge(&mut self, other: InMemoryIndex) {
+        self.heap_size += other.heap_size;
         for (term, hits) in other.map {
-            self.map.entry(term)
-                .or_default()
-                .extend(hits);
+            if let Some(existing) = self.map.get_mut(&term) {
+                // Only one copy of the term is kept.
+                self.heap_size -= TERM_OVERHEAD + term.len();
+                existing.extend(hits);
+            } else {
+                self.map.insert(term, hits);
+            }
         }
         self.word_count += other.word_count;
     }
@@ -100,11 +230,90 @@ impl InMemoryIndex {
         self.word_count == 0
     }
 
-    /// True if this index is large enough that we should dump it to disk rather
-    /// than keep adding more data to it.
-    pub fn is_large(&self) -> bool {
-        // This depends on how much memory your computer has, of course.
-        const REASONABLE_SIZE: usize = 100_000_000;
-        self.word_count > REASONABLE_SIZE
+    /// Approximately how many bytes of memory this index is using.
+    ///
+    /// This counts the terms and hit data, plus an estimate of the overhead of
+    /// the `HashMap` and `Vec`s holding them, so it's a much better guide to
+    /// when an index should be written to disk than `word_count` is.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    const TEXT: &str = "Straße, naïve café! 日本語のテキスト and\u{1F600}emoji; \
+                        ÉLAN vital — ünïcödé wörds ∂x/∂t = 0, plain ASCII too.";
+
+    /// Index `text` with `from_reader`, reading it `capacity` bytes at a
+    /// time, and return the parts it was flushed in, and the rest.
+    fn read_in_parts(text: &str, capacity: usize, part_size: usize) -> (Vec<InMemoryIndex>, InMemoryIndex) {
+        let mut parts = vec![];
+        let reader = BufReader::with_capacity(capacity, text.as_bytes());
+        let rest = InMemoryIndex::from_reader(7, reader, part_size, |part| {
+            parts.push(part);
+            Ok(())
+        })
+        .unwrap();
+        (parts, rest)
+    }
+
+    #[test]
+    fn from_reader_matches_from_single_document() {
+        let expected = InMemoryIndex::from_single_document(7, TEXT.to_string());
+        // Small chunks put every word, and every multi-byte character, across
+        // a chunk boundary for some capacity.
+        for capacity in 1..=9 {
+            let (parts, index) = read_in_parts(TEXT, capacity, usize::MAX);
+            assert!(parts.is_empty());
+            assert_eq!(index.map, expected.map, "capacity {capacity}");
+            assert_eq!(index.word_count, expected.word_count);
+        }
+    }
+
+    #[test]
+    fn from_reader_rejects_invalid_utf8() {
+        let bytes = b"caf\xe9 latte";
+        let result = InMemoryIndex::from_reader(0, BufReader::with_capacity(2, &bytes[..]), usize::MAX, |_| Ok(()));
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn from_reader_flushes_parts() {
+        let expected = InMemoryIndex::from_single_document(7, TEXT.to_string());
+        let (mut parts, rest) = read_in_parts(TEXT, 4, 1);
+        assert!(parts.len() > 1);
+        parts.push(rest);
+
+        // Joining each term's hits from all the parts, in order, gives the
+        // same offsets as indexing the whole text at once.
+        let mut offsets: HashMap<String, Vec<u32>> = HashMap::new();
+        for part in &parts {
+            for (term, hits) in &part.map {
+                assert_eq!(hits.len(), 1, "one hit per term per part");
+                assert_eq!(hit_doc_id(&hits[0]), 7);
+                offsets.entry(term.clone()).or_default().extend(hit_offsets(&hits[0]));
+            }
+        }
+        let expected: HashMap<String, Vec<u32>> = expected
+            .map
+            .iter()
+            .map(|(term, hits)| (term.clone(), hit_offsets(&hits[0]).collect()))
+            .collect();
+        assert_eq!(offsets, expected);
+    }
+
+    #[test]
+    fn from_reader_holds_back_only_so_much() {
+        // A "word" longer than `MAX_HELD_BACK`, straddling many chunks, is cut
+        // off rather than held in memory until the end.
+        let text = format!("{} end", "a".repeat(3 * MAX_HELD_BACK));
+        let (_, index) = read_in_parts(&text, 1000, usize::MAX);
+        assert!(index.map.contains_key("end"));
+        assert!(index.map.keys().all(|term| term.len() <= MAX_HELD_BACK + 1000));
+        assert!(index.word_count > 2);
     }
 }
@@ -0,0 +1,324 @@
+//! Looking inside an index: statistics, consistency checks, and dumps of
+//! index files.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::docs::DocumentTable;
+use crate::error::{Context, Error, Section};
+use crate::index::{hit_doc_id, hit_offsets, Hit, HIT_HEADER_SIZE};
+use crate::paths::IndexPaths;
+use crate::progress::{format_bytes, json_string};
+use crate::publish::Manifest;
+use crate::read::{read_contents, read_header, read_hit_header, Entry, IndexFileReader, RangeReader};
+use crate::write::HEADER_SIZE;
+
+/// Print a summary of the index named by `paths`: how many documents it has,
+/// and the size and level of each segment.
+pub fn print_stats(paths: &IndexPaths) -> io::Result<()> {
+    let manifest = Manifest::load_existing(paths)?;
+    let table = manifest.read_table(paths)?;
+    let deleted = (0..table.len()).filter(|&doc_id| table.is_deleted(doc_id)).count();
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    writeln!(out, "generation {}", manifest.generation)?;
+    writeln!(out, "documents  {} ({deleted} deleted)", table.len())?;
+    writeln!(out, "segments   {}", manifest.segments.len())?;
+    let mut total_bytes = 0;
+    for (level, path) in &manifest.segments {
+        let bytes = fs::metadata(path).in_file(path)?.len();
+        total_bytes += bytes;
+        let contents = read_contents(path)?;
+        let hits: u64 = contents.iter().map(|entry| entry.df as u64).sum();
+        writeln!(
+            out,
+            "  level {level:<2} {}  {} terms, {hits} hits, {}",
+            path.display(),
+            contents.len(),
+            format_bytes(bytes),
+        )?;
+    }
+    writeln!(out, "total size {}", format_bytes(total_bytes))?;
+    out.flush()
+}
+
+/// Check the index named by `paths` for damage, reading every segment in
+/// full. Problems are printed as they're found; if there are any, this
+/// returns an error.
+pub fn verify(paths: &IndexPaths) -> io::Result<()> {
+    let manifest = Manifest::load_existing(paths)?;
+    let table = manifest.read_table(paths)?;
+
+    // Each segment's documents must all come after the previous segment's.
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut problems = 0;
+    let mut last_doc_id = None;
+    for (_, path) in &manifest.segments {
+        let mut report = |problem: String| {
+            problems += 1;
+            writeln!(out, "{}: {problem}", path.display())
+        };
+        match verify_segment(path, &table, &mut report) {
+            Ok(Some((first, last))) => {
+                if last_doc_id.is_some_and(|previous| first <= previous) {
+                    report(format!("document {first} is out of order with the segment before"))?;
+                }
+                last_doc_id = Some(last);
+            }
+            Ok(None) => {}
+            // Errors that already say which file they're about are printed
+            // as they are.
+            Err(err) => match Error::from(err) {
+                Error::Io(err) => report(err.to_string())?,
+                err => {
+                    problems += 1;
+                    writeln!(out, "{err}")?;
+                }
+            },
+        }
+    }
+
+    if problems > 0 {
+        out.flush()?;
+        return Err(Error::Damaged { problems }.into());
+    }
+    writeln!(
+        out,
+        "ok: {} segments, {} documents",
+        manifest.segments.len(),
+        table.len(),
+    )?;
+    out.flush()
+}
+
+/// Check one segment, calling `report` for each problem found. Returns the
+/// lowest and highest document ids in it, if it has any. Errors from `report`
+/// are passed on.
+fn verify_segment<F>(
+    path: &Path,
+    table: &DocumentTable,
+    report: &mut F,
+) -> io::Result<Option<(usize, usize)>>
+where
+    F: FnMut(String) -> io::Result<()>,
+{
+    let mut file = File::open(path).in_file(path)?;
+    let contents_offset = read_header(&mut file, path)?.contents_offset;
+
+    let mut range: Option<(usize, usize)> = None;
+    let mut previous_term: Option<String> = None;
+    let mut expected_offset = HEADER_SIZE;
+    for entry in read_contents(path)? {
+        let term = &entry.term;
+        if previous_term.as_ref().is_some_and(|previous| previous >= term) {
+            report(format!("term {term:?} is out of order"))?;
+        }
+        if entry.offset != expected_offset {
+            report(format!("term {term:?}: data at offset {}, expected {expected_offset}", entry.offset))?;
+        }
+        let Some(end) = entry.offset.checked_add(entry.nbytes).filter(|&end| end <= contents_offset) else {
+            report(format!("term {term:?}: data runs into the table of contents"))?;
+            return Ok(range);
+        };
+        expected_offset = end;
+
+        // Only the document ids are checked, so each hit's offsets are read
+        // past without keeping them.
+        let mut hits = RangeReader::new(&file, entry.offset, entry.nbytes);
+        let mut remaining = entry.nbytes;
+        let mut count = 0;
+        let mut previous_doc_id = None;
+        while remaining > 0 {
+            let offset = entry.offset + (entry.nbytes - remaining);
+            let (header, len) = match read_hit_header(&mut hits, remaining) {
+                Ok(hit) => hit,
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    report(format!("term {term:?}: hit runs past the end of the term's data"))?;
+                    break;
+                }
+                Err(err) => return Err(err).in_index_file(path, Section::Postings, offset),
+            };
+            io::copy(&mut (&mut hits).take(len - HIT_HEADER_SIZE as u64), &mut io::sink())
+                .in_index_file(path, Section::Postings, offset)?;
+            remaining -= len;
+            let doc_id = hit_doc_id(&header);
+            if doc_id >= table.len() {
+                report(format!("term {term:?}: document {doc_id} isn't in the document table"))?;
+            }
+            if previous_doc_id.is_some_and(|previous| doc_id <= previous) {
+                report(format!("term {term:?}: document {doc_id} is out of order"))?;
+            }
+            previous_doc_id = Some(doc_id);
+            range = Some(match range {
+                Some((first, last)) => (first.min(doc_id), last.max(doc_id)),
+                None => (doc_id, doc_id),
+            });
+            count += 1;
+        }
+        if count != entry.df {
+            report(format!("term {term:?}: {count} hits, but df is {}", entry.df))?;
+        }
+        previous_term = Some(entry.term);
+    }
+    if expected_offset != contents_offset {
+        report(format!(
+            "{} bytes between the last term's data and the table of contents",
+            contents_offset - expected_offset,
+        ))?;
+    }
+    Ok(range)
+}
+
+/// How `dump` prints what it finds.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Tab-separated text. The header is on lines starting with `#`; then
+    /// each table of contents entry has a line, `TERM DF OFFSET NBYTES`,
+    /// followed by a line for each of its hits, if they were asked for,
+    /// indented by a tab: `DOC_ID COUNT OFFSETS...`.
+    Text,
+
+    /// One JSON object per line: first the header, then each table of
+    /// contents entry, with a `hits` array, if they were asked for.
+    Json,
+}
+
+/// Print what's in the index file `path`: its header, then each entry in its
+/// table of contents, with the number of documents the term appears in and
+/// where its data is. The hits for the terms in `terms` are decoded and
+/// printed too.
+///
+/// The file is read from beginning to end once, and left alone.
+pub fn dump(path: &Path, terms: &[String], format: DumpFormat) -> io::Result<()> {
+    let mut reader = IndexFileReader::open(path)?;
+    let header = reader.header().clone();
+    let (size, contents_start) = (header.len, header.contents_offset);
+    let magic = String::from_utf8_lossy(&header.magic);
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    match format {
+        DumpFormat::Text => {
+            writeln!(out, "# {}", path.display())?;
+            writeln!(out, "# magic {magic:?}, format version {}", header.version)?;
+            writeln!(
+                out,
+                "# {size} bytes; table of contents at byte {contents_start}, {} bytes",
+                size - contents_start,
+            )?;
+        }
+        DumpFormat::Json => writeln!(
+            out,
+            "{{\"path\":{},\"magic\":{},\"version\":{},\"size\":{size},\"contents_offset\":{contents_start}}}",
+            json_string(&path.display().to_string()),
+            json_string(&magic),
+            header.version,
+        )?,
+    }
+    while let Some(entry) = reader.peek() {
+        if terms.contains(&entry.term) {
+            let (entry, hits) = reader.read_entry_hits()?;
+            write_entry(&mut out, &entry, Some(&hits), format)?;
+        } else {
+            let entry = reader.skip_entry()?;
+            write_entry(&mut out, &entry, None, format)?;
+        }
+    }
+    out.flush()
+}
+
+/// Print one table of contents entry for `dump`, and its hits, if any.
+fn write_entry<W: Write>(
+    out: &mut W,
+    entry: &Entry,
+    hits: Option<&[Hit]>,
+    format: DumpFormat,
+) -> io::Result<()> {
+    let offsets = |hit: &Hit, separator: &str| {
+        hit_offsets(hit).map(|offset| offset.to_string()).collect::<Vec<_>>().join(separator)
+    };
+    match format {
+        DumpFormat::Text => {
+            writeln!(out, "{}\t{}\t{}\t{}", entry.term, entry.df, entry.offset, entry.nbytes)?;
+            for hit in hits.unwrap_or_default() {
+                let count = hit_offsets(hit).count();
+                writeln!(out, "\t{}\t{count}\t{}", hit_doc_id(hit), offsets(hit, " "))?;
+            }
+        }
+        DumpFormat::Json => {
+            write!(
+                out,
+                "{{\"term\":{},\"df\":{},\"offset\":{},\"nbytes\":{}",
+                json_string(&entry.term),
+                entry.df,
+                entry.offset,
+                entry.nbytes,
+            )?;
+            if let Some(hits) = hits {
+                write!(out, ",\"hits\":[")?;
+                for (i, hit) in hits.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    write!(
+                        out,
+                        "{{\"doc_id\":{},\"offsets\":[{}]}}",
+                        hit_doc_id(hit),
+                        offsets(hit, ","),
+                    )?;
+                }
+                write!(out, "]")?;
+            }
+            writeln!(out, "}}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+    use crate::memory::DEFAULT_MEMORY_LIMIT;
+    use crate::paths::DEFAULT_INDEX_NAME;
+    use crate::tests::{build, options, test_dir};
+
+    #[test]
+    fn verify_reads_through_large_hits() {
+        let dir = test_dir("verify-large-hits");
+        let big = dir.join("big.txt");
+        fs::write(&big, "word ".repeat(1_000_000)).unwrap();
+        let small = dir.join("small.txt");
+        fs::write(&small, "word other").unwrap();
+        let output = dir.join("index");
+        build(&[big, small], &output, true, &options(1, DEFAULT_MEMORY_LIMIT), false);
+
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output);
+        let manifest = Manifest::load_existing(&paths).unwrap();
+        let table = manifest.read_table(&paths).unwrap();
+        let segment = &manifest.segments[0].1;
+        let verify = || {
+            let mut problems = vec![];
+            let mut report = |problem| {
+                problems.push(problem);
+                Ok(())
+            };
+            let range = verify_segment(segment, &table, &mut report).unwrap();
+            (range, problems)
+        };
+        assert_eq!(verify(), (Some((0, 1)), vec![]));
+
+        // A hit that claims more offsets than the term has room for is
+        // reported, not read into memory.
+        let entry = read_contents(segment).unwrap().into_iter().find(|entry| entry.term == "word").unwrap();
+        let mut file = OpenOptions::new().write(true).open(segment).unwrap();
+        file.seek(SeekFrom::Start(entry.offset + 4)).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+        let (_, problems) = verify();
+        assert_eq!(problems[0], "term \"word\": hit runs past the end of the term's data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
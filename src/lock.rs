@@ -0,0 +1,47 @@
+//! Keeping two programs from changing the same index at once.
+//!
+//! Readers never need a lock: a published generation is never changed (see
+//! `publish`). But two writers would trample each other's checkpoint,
+//! temporary files and segment numbers, and whichever published last would
+//! silently throw away the other's work. So every command that changes an
+//! index holds an exclusive lock on `NAME.lock` for as long as it runs. The
+//! lock belongs to the open file, so the operating system releases it when
+//! the program exits, however that happens; there's no stale lock to clean
+//! up after a crash.
+//!
+//! The lock file itself is left in place. Deleting it would let a program
+//! that opened it just before lock the deleted file, while another locks a
+//! new one by the same name.
+
+use std::fs::{File, OpenOptions, TryLockError};
+
+use crate::error::{Context, Error};
+use crate::paths::IndexPaths;
+
+/// The right to change the index. It lasts until this is dropped.
+pub struct WriteLock {
+    _file: File,
+}
+
+impl WriteLock {
+    /// Lock the index named by `paths`, which must be in a directory that
+    /// exists. If another program already has it locked, this fails with
+    /// `Error::Busy`, rather than waiting for who knows how long.
+    pub fn acquire(paths: &IndexPaths) -> Result<WriteLock, Error> {
+        if !paths.dir().is_dir() {
+            return Err(Error::NoIndex(paths.dir().to_path_buf()));
+        }
+        let path = paths.lock();
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .in_file(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(WriteLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(Error::Busy(path)),
+            Err(TryLockError::Error(err)) => Err(Error::File { path, position: None, err }),
+        }
+    }
+}
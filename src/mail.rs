@@ -0,0 +1,458 @@
+//! Reading email: mbox files, Maildir directories, and the messages in them.
+//!
+//! Each message is a separate document. The `From`, `To`, `Subject`, and
+//! `Date` headers are indexed as fields, and the body is whatever text parts
+//! the message has, after undoing any quoted-printable or base64 encoding.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use crate::error::Context;
+use crate::extract::html_to_text;
+use crate::source::{Document, Field};
+
+/// Headers that are indexed as fields, and the field names they're indexed as.
+const HEADER_FIELDS: &[(&str, &str)] = &[
+    ("from", "from"),
+    ("to", "to"),
+    ("subject", "subject"),
+    ("date", "date"),
+];
+
+/// True if `path` looks like a Maildir: a directory with `cur` and `new`
+/// subdirectories.
+pub fn is_maildir(path: &Path) -> bool {
+    path.join("cur").is_dir() && path.join("new").is_dir()
+}
+
+/// Read every message in the Maildir at `path`, passing each one to `f`.
+///
+/// Messages are read from the `cur` and `new` subdirectories, in filename
+/// order. Each document is named by the path of the message file.
+pub fn read_maildir<F>(path: &Path, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    for subdir in ["cur", "new"] {
+        let dir = path.join(subdir);
+        let mut messages = vec![];
+        for entry in dir.read_dir().in_file(&dir)? {
+            let entry = entry.in_file(&dir)?;
+            if entry.file_type().in_file(&entry.path())?.is_file() {
+                messages.push(entry.path());
+            }
+        }
+        messages.sort();
+
+        for message in messages {
+            let raw = fs::read(&message).in_file(&message)?;
+            let doc = parse_message(message.display().to_string(), &raw);
+            if f(doc)?.is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Read every message in the mbox file at `path`, which is open as `reader`,
+/// passing each one to `f`.
+///
+/// The file is read one message at a time, so mailboxes much larger than
+/// memory are fine. Messages are named like archive members, with the path of
+/// the mailbox, `!/`, and the message number, counting from 1.
+pub fn read_mbox<R, F>(path: &Path, mut reader: R, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    R: BufRead,
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    let mut count = 0;
+    let mut message: Vec<u8> = vec![];
+    let mut line = vec![];
+    let mut after_blank = true;
+    loop {
+        line.clear();
+        let done = reader.read_until(b'\n', &mut line).in_file(path)? == 0;
+
+        // A "From " line following a blank line starts a new message.
+        if done || (after_blank && line.starts_with(b"From ")) {
+            if count > 0 {
+                let name = format!("{}!/{count}", path.display());
+                if f(parse_message(name, &message))?.is_break() {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+            if done {
+                break;
+            }
+            count += 1;
+            message.clear();
+            after_blank = false;
+            continue;
+        }
+
+        after_blank = line == b"\n" || line == b"\r\n";
+        if count == 0 {
+            // Junk before the first "From " line. Ignore it.
+            continue;
+        }
+
+        // Lines in the body that look like "From " lines are escaped by
+        // adding '>' to the front. Undo that.
+        let start = line.iter().position(|&b| b != b'>').unwrap_or(0);
+        if start > 0 && line[start..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(&line);
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Parse a raw email message into a document.
+fn parse_message(name: String, raw: &[u8]) -> Document {
+    let (headers, body) = split_headers(raw);
+
+    let mut text = String::new();
+    extract_body_text(&headers, body, &mut text);
+
+    let mut doc = Document::new(name, text);
+    for &(header, field) in HEADER_FIELDS {
+        if let Some(value) = find_header(&headers, header) {
+            doc.fields.push(Field {
+                name: field,
+                text: decode_encoded_words(value),
+            });
+        }
+    }
+    doc
+}
+
+/// A message header, with continuation lines already joined.
+struct Header {
+    name: String,
+    value: String,
+}
+
+/// Split a message (or MIME part) into its headers and body.
+fn split_headers(raw: &[u8]) -> (Vec<Header>, &[u8]) {
+    let mut headers: Vec<Header> = vec![];
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let end = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+        let (line, after) = rest.split_at(end);
+        rest = after;
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            // A continuation of the previous header.
+            if let Some(last) = headers.last_mut() {
+                last.value.push(' ');
+                last.value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push(Header {
+                name: name.trim().to_lowercase(),
+                value: value.trim().to_string(),
+            });
+        }
+    }
+    (headers, rest)
+}
+
+fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers.iter().find(|h| h.name == name).map(|h| h.value.as_str())
+}
+
+/// Find a parameter, like `boundary` or `charset`, in a header value like
+/// `multipart/mixed; boundary="xyz"`.
+fn header_param<'a>(value: &'a str, param: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|p| {
+        let (key, val) = p.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(param) {
+            Some(val.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Append the text of a message body to `out`, descending into multipart
+/// bodies. Only `text/plain` and `text/html` parts are indexed; attachments
+/// and other binary parts are skipped.
+fn extract_body_text(headers: &[Header], body: &[u8], out: &mut String) {
+    let content_type = find_header(headers, "content-type").unwrap_or("text/plain");
+    let mime_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+    if mime_type.starts_with("multipart/") {
+        if let Some(boundary) = header_param(content_type, "boundary") {
+            let mut parts: Vec<_> = split_multipart(body, boundary)
+                .into_iter()
+                .map(split_headers)
+                .collect();
+
+            // The parts of a multipart/alternative body are the same content
+            // in different formats. Index just one, preferring plain text.
+            if mime_type == "multipart/alternative" {
+                let is_plain = |(headers, _): &(Vec<Header>, &[u8])| {
+                    find_header(headers, "content-type")
+                        .is_none_or(|ct| ct.to_lowercase().starts_with("text/plain"))
+                };
+                if let Some(i) = parts.iter().position(is_plain) {
+                    parts = vec![parts.swap_remove(i)];
+                }
+            }
+
+            for (part_headers, part_body) in parts {
+                extract_body_text(&part_headers, part_body, out);
+            }
+        }
+        return;
+    }
+    if mime_type != "text/plain" && mime_type != "text/html" {
+        return;
+    }
+    let is_attachment = find_header(headers, "content-disposition")
+        .is_some_and(|d| d.trim_start().to_lowercase().starts_with("attachment"));
+    if is_attachment {
+        return;
+    }
+
+    let encoding = find_header(headers, "content-transfer-encoding")
+        .unwrap_or("7bit")
+        .to_lowercase();
+    let bytes = match encoding.as_str() {
+        "quoted-printable" => decode_quoted_printable(body),
+        "base64" => decode_base64(body),
+        _ => body.to_vec(),
+    };
+    let text = decode_charset(&bytes, header_param(content_type, "charset"));
+
+    if mime_type == "text/html" {
+        out.push_str(&html_to_text(&text));
+    } else {
+        out.push_str(&text);
+    }
+    out.push('\n');
+}
+
+/// Split the body of a multipart message into its parts.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+    let mut parts = vec![];
+    let mut part_start = None;
+    let mut pos = 0;
+    while pos < body.len() {
+        let end = body[pos..].iter().position(|&b| b == b'\n').map_or(body.len(), |i| pos + i + 1);
+        let line = &body[pos..end];
+        if line.starts_with(delimiter) {
+            if let Some(start) = part_start {
+                parts.push(&body[start..pos]);
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                return parts;
+            }
+            part_start = Some(end);
+        }
+        pos = end;
+    }
+    if let Some(start) = part_start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Decode text in the given character set. We only really know about UTF-8
+/// and Latin-1; anything else is treated as UTF-8, replacing invalid bytes.
+fn decode_charset(bytes: &[u8], charset: Option<&str>) -> String {
+    match charset.map(|c| c.to_lowercase()).as_deref() {
+        Some("iso-8859-1" | "latin1" | "windows-1252") => {
+            bytes.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Decode a quoted-printable body, as described in RFC 2045.
+fn decode_quoted_printable(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'=' {
+            out.push(input[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &input[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            // Soft line break.
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let Some(byte) = rest.get(..2).and_then(decode_hex_byte) {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(b'=');
+            i += 1;
+        }
+    }
+    out
+}
+
+fn decode_hex_byte(hex: &[u8]) -> Option<u8> {
+    let s = std::str::from_utf8(hex).ok()?;
+    u8::from_str_radix(s, 16).ok()
+}
+
+/// Decode base64, ignoring whitespace and any other junk characters.
+fn decode_base64(input: &[u8]) -> Vec<u8> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' | b'-' => Some(62),
+            b'/' | b'_' => Some(63),
+            _ => None,
+        }
+    }
+
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in input {
+        if c == b'=' {
+            break;
+        }
+        let Some(v) = value(c) else { continue };
+        acc = (acc << 6) | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    out
+}
+
+/// Decode RFC 2047 "encoded words" in a header value, like
+/// `=?utf-8?q?caf=C3=A9?=` or `=?UTF-8?B?Y2Fmw6k=?=`.
+fn decode_encoded_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("=?") {
+        out.push_str(&rest[..start]);
+        let word = &rest[start + 2..];
+        let decoded = (|| {
+            let (charset, word) = word.split_once('?')?;
+            let (encoding, word) = word.split_once('?')?;
+            let end = word.find("?=")?;
+            let bytes = match encoding {
+                "B" | "b" => decode_base64(&word.as_bytes()[..end]),
+                "Q" | "q" => decode_quoted_printable(word[..end].replace('_', " ").as_bytes()),
+                _ => return None,
+            };
+            let consumed = value.len() - word.len() + end + 2;
+            Some((decode_charset(&bytes, Some(charset)), consumed))
+        })();
+        match decoded {
+            Some((text, consumed)) => {
+                out.push_str(&text);
+                rest = &value[consumed..];
+            }
+            None => {
+                out.push_str("=?");
+                rest = word;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Body;
+
+    /// The in-memory text of a document's body.
+    fn body_text(doc: &Document) -> &str {
+        match &doc.body {
+            Body::Text(text) => text,
+            Body::File(path) => panic!("unexpected file body {}", path.display()),
+        }
+    }
+
+    #[test]
+    fn quoted_printable() {
+        assert_eq!(decode_quoted_printable(b"caf=C3=A9 au lait"), "café au lait".as_bytes());
+        // Soft line breaks, with either line ending, vanish.
+        assert_eq!(decode_quoted_printable(b"long=\r\nline=\nhere"), b"longlinehere");
+        // An `=` that doesn't start an escape is kept as it is.
+        assert_eq!(decode_quoted_printable(b"a =ZZ b="), b"a =ZZ b=");
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64(b"Y2Fmw6k="), "café".as_bytes());
+        // Line breaks and other junk are skipped.
+        assert_eq!(decode_base64(b"aGVs\r\nbG8g\n d29y bGQ="), b"hello world");
+        // The URL-safe alphabet is accepted too.
+        assert_eq!(decode_base64(b"-_8"), [0xfb, 0xff]);
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(decode_encoded_words("=?utf-8?q?caf=C3=A9_au_lait?="), "café au lait");
+        assert_eq!(decode_encoded_words("Re: =?UTF-8?B?Y2Fmw6k=?= menu"), "Re: café menu");
+        assert_eq!(decode_encoded_words("=?iso-8859-1?Q?caf=E9?="), "café");
+        // Anything that isn't a well-formed encoded word is left alone.
+        assert_eq!(decode_encoded_words("1 =? 2 and =?utf-8?x?y?="), "1 =? 2 and =?utf-8?x?y?=");
+    }
+
+    #[test]
+    fn parse_message_fields_and_parts() {
+        let raw = b"From: Alice <alice@example.com>\r\n\
+                    Subject: =?utf-8?q?caf=C3=A9?=\r\n \
+                    menu\r\n\
+                    Content-Type: multipart/mixed; boundary=\"xyz\"\r\n\
+                    \r\n\
+                    preamble\r\n\
+                    --xyz\r\n\
+                    Content-Type: text/plain; charset=utf-8\r\n\
+                    Content-Transfer-Encoding: quoted-printable\r\n\
+                    \r\n\
+                    cr=C3=A8me br=\r\nul=C3=A9e\r\n\
+                    --xyz\r\n\
+                    Content-Type: text/html\r\n\
+                    Content-Transfer-Encoding: base64\r\n\
+                    \r\n\
+                    PHA+dGFydDwvcD4=\r\n\
+                    --xyz\r\n\
+                    Content-Type: text/plain\r\n\
+                    Content-Disposition: attachment; filename=\"notes.txt\"\r\n\
+                    \r\n\
+                    secret\r\n\
+                    --xyz--\r\n";
+        let doc = parse_message("inbox!/1".to_string(), raw);
+
+        let fields: Vec<(&str, &str)> = doc.fields.iter().map(|f| (f.name, f.text.as_str())).collect();
+        assert_eq!(fields, [("from", "Alice <alice@example.com>"), ("subject", "café menu")]);
+
+        let body = body_text(&doc);
+        assert!(body.contains("crème brulée"));
+        assert!(body.contains("tart"));
+        assert!(!body.contains("<p>"));
+        assert!(!body.contains("preamble"));
+        assert!(!body.contains("secret"));
+    }
+}
@@ -7,88 +7,351 @@
 //! *   `run_single_threaded` simply does everything in one thread, in
 //!     the most straightforward possible way.
 //!
-//! *   Then, we break the work into a five-stage pipeline so that we can run
-//!     it on multiple CPUs. `run_pipeline` puts the five stages together.
+//! *   Then, we break the work into a seven-stage pipeline so that we can run
+//!     it on multiple CPUs. `run_pipeline` puts the seven stages together.
 //!
-//! The `main` function at the end handles command-line arguments. It calls one
-//! of the two functions above to do the work.
+//! The command line is handled by the module `cli`. Commands that build an
+//! index call `run`, which calls one of the two functions above to do the
+//! work; the module `update` uses it to keep an index up to date.
 
 mod index;
 mod read;
 mod write;
 mod merge;
 mod tmp;
+mod source;
+mod docs;
+mod extract;
+mod mail;
+mod split;
+mod memory;
+mod progress;
+mod cancel;
+mod checkpoint;
+mod paths;
+mod publish;
+mod space;
+mod tombstones;
+mod search;
+mod lock;
+mod stamp;
+mod watch;
+mod inspect;
+mod error;
+mod update;
+mod cli;
 
-use std::{fs, io};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use argparse::{ArgumentParser, StoreTrue, Collect};
 
 use crate::index::InMemoryIndex;
 use crate::write::write_index_to_tmp_file;
-use crate::merge::FileMerge;
+use crate::merge::{join_parts, FileMerge, MergeOptions};
 use crate::tmp::TmpDir;
+use crate::source::{read_documents, Body, Document, InputMode};
+use crate::docs::DocumentTable;
+use crate::extract::extract_text;
+use crate::split::{split_document, Splitter};
+use crate::memory::{flush_size, MemoryBudget};
+use crate::progress::{Event, Progress, ProgressMode};
+use crate::cancel::Cancel;
+use crate::checkpoint::Checkpoint;
+use crate::paths::IndexPaths;
+use crate::publish::Manifest;
+use crate::space::{check_space, SpaceCheck};
+use crate::stamp::HashingReader;
+use crate::error::{Context, Error};
+
+/// Options that control how documents are read and indexed.
+#[derive(Clone)]
+struct IndexOptions {
+    /// How to read documents out of the files named on the command line.
+    mode: InputMode,
+
+    /// If true, titles and headings in marked-up documents are indexed as
+    /// separate fields.
+    use_fields: bool,
+
+    /// If set, each file is split into many smaller documents.
+    splitter: Option<Splitter>,
+
+    /// Number of threads to use for tokenizing and indexing documents. (Only
+    /// `run_pipeline` pays attention to this.)
+    indexing_threads: usize,
+
+    /// Approximate limit, in bytes, on the memory used by in-memory indexes.
+    memory_limit: usize,
+
+    /// How to merge temporary files. (`run_single_threaded` ignores the
+    /// number of merge threads, and does all merging itself.)
+    merge: MergeOptions,
+}
+
+impl IndexOptions {
+    /// A summary of the options that affect what goes into the index. An
+    /// interrupted run can only be resumed with the same options.
+    fn checkpoint_key(&self) -> String {
+        let mode = match self.mode {
+            InputMode::Files => "files",
+            InputMode::Mail => "mail",
+        };
+        let split = match &self.splitter {
+            None => "none".to_string(),
+            Some(Splitter::Lines) => "lines".to_string(),
+            Some(Splitter::Records(re)) => format!("records:{}", re.as_str()),
+        };
+        format!("mode={mode} fields={} split={split}", self.use_fields)
+    }
+}
+
+/// Options that control how an indexing run goes, as opposed to what goes into
+/// the index.
+#[derive(Clone)]
+struct RunOptions {
+    /// Do all the work on one thread.
+    single_threaded: bool,
+
+    /// Add documents to the current index, instead of replacing it.
+    add: bool,
+
+    /// Pick up where an interrupted run left off, instead of starting over.
+    resume: bool,
+
+    /// How to report progress.
+    progress_mode: ProgressMode,
+
+    /// What to do if there might not be enough disk space.
+    space_check: SpaceCheck,
+}
+
+/// Size of the buffer used when reading a document as a stream.
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A document that's streamed from disk has its index written to a temporary
+/// file, a part at a time. Each part can use this fraction of the memory limit.
+const STREAM_PARTS_PER_LIMIT: usize = 8;
+
+/// The index of a single document.
+enum DocumentIndex {
+    /// The usual case: an index in memory, to be merged with others.
+    Memory(InMemoryIndex),
+
+    /// A document whose index was too big for memory, so it's already been
+    /// written to a temporary file, to be merged with the others on disk.
+    File(PathBuf),
+}
+
+/// Index a single document, including any extra fields it has, and report it
+/// to `progress`. Documents that are too big to have been loaded into memory
+/// in advance are read from disk here, filling in their stamps' hashes as
+/// they go; if their indexes are too big to keep in memory, they're written
+/// to temporary files in `tmp_dir`.
+///
+/// This can fail only for documents that are read from disk here.
+fn index_document(
+    doc_id: usize,
+    doc: Document,
+    options: &IndexOptions,
+    tmp_dir: &TmpDir,
+    progress: &Progress,
+) -> io::Result<DocumentIndex> {
+    let mut parts = vec![];
+    let mut words = 0;
+    let (mut index, bytes) = match doc.body {
+        Body::Text(text) => {
+            let bytes = text.len() as u64;
+            (InMemoryIndex::from_single_document(doc_id, text), bytes)
+        }
+        Body::File(path) => {
+            let file = File::open(&path).in_file(&path)?;
+            let bytes = file.metadata().in_file(&path)?.len();
+            let mut file = HashingReader::new(file);
+            let reader = BufReader::with_capacity(STREAM_BUFFER_SIZE, &mut file);
+            let part_size = options.memory_limit / STREAM_PARTS_PER_LIMIT;
+            let index = InMemoryIndex::from_reader(doc_id, reader, part_size, |part| {
+                words += part.word_count;
+                parts.push(write_index_to_tmp_file(part, tmp_dir, progress)?);
+                Ok(())
+            })
+            .in_file(&path)?;
+            if let Some(stamp) = &doc.stamp {
+                stamp.finish(file.finish().in_file(&path)?);
+            }
+            (index, bytes)
+        }
+    };
+    for field in doc.fields {
+        index.add_field(doc_id, field.name, &field.text);
+    }
+    words += index.word_count;
+    progress.event(Event::DocumentIndexed { doc_id, bytes, words });
+    if parts.is_empty() {
+        return Ok(DocumentIndex::Memory(index));
+    }
+
+    if !index.is_empty() {
+        parts.push(write_index_to_tmp_file(index, tmp_dir, progress)?);
+    }
+    let file = join_parts(doc_id, &parts, tmp_dir, progress)?;
+    tmp_dir.remove(&parts)?;
+    Ok(DocumentIndex::File(file))
+}
+
+/// The size of an input file, for estimating how long indexing will take.
+/// Directories (that is, Maildirs) count as zero.
+fn input_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |m| if m.is_file() { m.len() } else { 0 })
+}
 
 /// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
-fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf) -> io::Result<()> {
+/// storing it in the files named by `paths`.
+///
+/// When adding to an existing index, `table` holds its documents, and the new
+/// ones are numbered after them. Documents already saved in temporary files
+/// according to `checkpoint` are skipped.
+fn run_single_threaded(
+    documents: Vec<PathBuf>,
+    paths: &IndexPaths,
+    options: &IndexOptions,
+    checkpoint: Checkpoint,
+    mut table: DocumentTable,
+    progress: &Progress,
+    cancel: &Cancel,
+) -> io::Result<()> {
     // If all the documents fit comfortably in memory, we'll create the whole
     // index in memory.
     let mut accumulated_index = InMemoryIndex::new();
 
+    // A tool for generating temporary filenames. It also deletes any
+    // temporary files that are left over if something goes wrong.
+    let tmp_dir = TmpDir::new(paths.tmp_dir(), paths.name())?;
+
     // If not, then as memory fills up, we'll write largeish temporary index
     // files to disk, saving the temporary filenames in `merge` so that later we
     // can merge them all into a single huge file.
-    let mut merge = FileMerge::new(&output_dir);
+    let resume_from = checkpoint.documents;
+    let mut merge = FileMerge::new(
+        paths.clone(),
+        tmp_dir.clone(),
+        checkpoint,
+        table.deleted().clone(),
+        MergeOptions { threads: 0, ..options.merge },
+        progress.clone(),
+        cancel.clone(),
+    );
 
-    // A tool for generating temporary filenames.
-    let mut tmp_dir = TmpDir::new(&output_dir);
+    // This adds the contents of one document to the in-memory
+    // `accumulated_index`.
+    let mut add_document = |doc: Document| {
+        cancel.check()?;
+        let doc_id = table.push(doc.name.clone(), doc.location, doc.stamp.clone());
+        if doc_id < resume_from {
+            return Ok(ControlFlow::Continue(()));
+        }
+        match index_document(doc_id, doc, options, &tmp_dir, progress)? {
+            DocumentIndex::Memory(index) => {
+                accumulated_index.merge(index);
+                if accumulated_index.heap_size() >= flush_size(options.memory_limit) {
+                    // To avoid running out of memory, dump `accumulated_index` to disk.
+                    let full_index = std::mem::take(&mut accumulated_index);
+                    let file = write_index_to_tmp_file(full_index, &tmp_dir, progress)?;
+                    merge.add_file(file, doc_id + 1)?;
+                }
+            }
+            DocumentIndex::File(file) => {
+                // The documents before this one go first, to keep the files
+                // in document order.
+                if !accumulated_index.is_empty() {
+                    let full_index = std::mem::take(&mut accumulated_index);
+                    let earlier = write_index_to_tmp_file(full_index, &tmp_dir, progress)?;
+                    merge.add_file(earlier, doc_id)?;
+                }
+                merge.add_file(file, doc_id + 1)?;
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    };
 
     // For each document in the set...
-    for (doc_id, filename) in documents.into_iter().enumerate() {
-        // ...load it into memory...
-        let text = fs::read_to_string(filename)?;
-
-        // ...and add its contents to the in-memory `accumulated_index`.
-        let index = InMemoryIndex::from_single_document(doc_id, text);
-        accumulated_index.merge(index);
-        if accumulated_index.is_large() {
-            // To avoid running out of memory, dump `accumulated_index` to disk.
-            let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-            merge.add_file(file)?;
-            accumulated_index = InMemoryIndex::new();
-        }
+    for filename in documents {
+        // ...load it into memory (an archive or mailbox holds many documents)...
+        let _ = read_documents(&filename, options.mode, |doc| {
+            // ...strip out any markup...
+            let doc = extract_text(doc, options.use_fields);
+
+            // ...optionally split it into smaller documents, and index it.
+            match &options.splitter {
+                Some(splitter) => split_document(doc, splitter, &mut add_document),
+                None => add_document(doc),
+            }
+        })?;
+        progress.event(Event::FileRead { path: &filename, bytes: input_size(&filename) });
     }
 
     // Done reading documents! Save the last data set to disk, then merge the
     // temporary index files if there are more than one.
     if !accumulated_index.is_empty() {
-        let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-        merge.add_file(file)?;
+        let file = write_index_to_tmp_file(accumulated_index, &tmp_dir, progress)?;
+        merge.add_file(file, table.len())?;
     }
-    merge.finish()
+    let generation = merge.finish(&table)?;
+    progress.event(Event::Finished {
+        path: &paths.manifest(generation),
+        documents: table.len(),
+        peak_tmp_bytes: tmp_dir.peak_usage(),
+    });
+    Ok(())
+}
+
+/// Spawn a thread for one stage of the pipeline. If the stage fails, `cancel`
+/// is set, so that all the other stages stop too.
+fn spawn_stage<T, F>(cancel: Cancel, f: F) -> thread::JoinHandle<io::Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    thread::spawn(move || cancel.on_error(f()))
 }
 
 /// Start a thread that loads documents from the filesystem into memory.
 ///
-/// `documents` is a list of filenames to load.
+/// `documents` is a list of filenames to load. Archives and mailboxes are
+/// opened and each of their members is sent along as a separate document;
+/// `mode` says whether the files are mailboxes. As each file is finished, it's
+/// reported to `progress`.
 ///
-/// This returns a pair of values: a receiver that receives the documents, as
-/// Strings; and a `JoinHandle` that can be used to wait for this thread to
-/// exit and to get the `io::Error` value if anything goes wrong.
+/// This returns a pair of values: a receiver that receives the documents; and
+/// a `JoinHandle` that can be used to wait for this thread to exit and to get
+/// the `io::Error` value if anything goes wrong.
 fn start_file_reader_thread(
     documents: Vec<PathBuf>,
-) -> (mpsc::Receiver<String>, thread::JoinHandle<io::Result<()>>) {
+    mode: InputMode,
+    progress: Progress,
+    cancel: Cancel,
+) -> (mpsc::Receiver<Document>, thread::JoinHandle<io::Result<()>>) {
     let (sender, receiver) = mpsc::sync_channel(32);
 
-    let handle = thread::spawn(move || {
+    let handle = spawn_stage(cancel.clone(), move || {
         for filename in documents {
-            let text = fs::read_to_string(filename)?;
-
-            if sender.send(text).is_err() {
+            let flow = read_documents(&filename, mode, |doc| {
+                if cancel.is_cancelled() {
+                    return Ok(ControlFlow::Break(()));
+                }
+                Ok(match sender.send(doc) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(_) => ControlFlow::Break(()),
+                })
+            })?;
+            if flow.is_break() {
                 break;
             }
+            progress.event(Event::FileRead { path: &filename, bytes: input_size(&filename) });
         }
         Ok(())
     });
@@ -96,24 +359,26 @@ fn start_file_reader_thread(
     (receiver, handle)
 }
 
-/// Start a thread that tokenizes each text and converts it into an in-memory
-/// index. (We assume that every document fits comfortably in memory.)
+/// Start a thread that strips markup from HTML, XML, and Markdown documents,
+/// leaving only the text that should be indexed.
 ///
-/// `texts` is the stream of documents from the file reader thread.
+/// `documents` is the stream of documents from the file reader thread. If
+/// `use_fields` is true, titles and headings are also split out into
+/// separate fields.
 ///
-/// This assigns each document a number. It returns a pair of values: a
-/// receiver, the sequence of in-memory indexes; and a `JoinHandle` that can be
-/// used to wait for this thread to exit. This stage of the pipeline is
-/// infallible (it performs no I/O, so there are no possible errors).
-fn start_file_indexing_thread(
-    texts: mpsc::Receiver<String>,
-) -> (mpsc::Receiver<InMemoryIndex>, thread::JoinHandle<()>) {
+/// This returns a pair: a receiver for the plain-text documents; and a
+/// `JoinHandle` that can be used to wait for this thread to exit. This stage
+/// of the pipeline is infallible.
+fn start_text_extraction_thread(
+    documents: mpsc::Receiver<Document>,
+    use_fields: bool,
+    cancel: Cancel,
+) -> (mpsc::Receiver<Document>, thread::JoinHandle<()>) {
     let (sender, receiver) = mpsc::sync_channel(32);
 
     let handle = thread::spawn(move || {
-        for (doc_id, text) in texts.into_iter().enumerate() {
-            let index = InMemoryIndex::from_single_document(doc_id, text);
-            if sender.send(index).is_err() {
+        for doc in documents {
+            if cancel.is_cancelled() || sender.send(extract_text(doc, use_fields)).is_err() {
                 break;
             }
         }
@@ -122,38 +387,215 @@ fn start_file_indexing_thread(
     (receiver, handle)
 }
 
+/// Start a thread that splits each document into smaller documents, and
+/// assigns each resulting document a number.
+///
+/// `documents` is the stream of documents from the text extraction thread. If
+/// `splitter` is `None`, they're not split, only numbered. They're added to
+/// `table`, which already holds any documents from an index we're adding to,
+/// so numbering carries on after those. Documents numbered below
+/// `first_doc_id` are already indexed (we're resuming an interrupted run), so
+/// they're added to the table but not sent along.
+///
+/// Document ids are assigned here, up front, rather than by the indexing
+/// threads, so that they don't depend on which indexing thread happens to
+/// finish first.
+///
+/// This returns a pair: a receiver for the numbered documents; and a
+/// `JoinHandle` that can be used to wait for this thread to exit and to get
+/// the table of document names, or the `io::Error` if anything goes wrong.
+/// (Documents too big to load into memory are read from disk by this thread,
+/// so it can fail.)
+fn start_document_splitting_thread(
+    documents: mpsc::Receiver<Document>,
+    splitter: Option<Splitter>,
+    mut table: DocumentTable,
+    first_doc_id: usize,
+    cancel: Cancel,
+) -> (mpsc::Receiver<(usize, Document)>, thread::JoinHandle<io::Result<DocumentTable>>) {
+    let (sender, receiver) = mpsc::sync_channel(32);
+
+    let handle = spawn_stage(cancel.clone(), move || {
+        let mut send = |doc: Document| {
+            if cancel.is_cancelled() {
+                return Ok(ControlFlow::Break(()));
+            }
+            let doc_id = table.push(doc.name.clone(), doc.location, doc.stamp.clone());
+            if doc_id < first_doc_id {
+                return Ok(ControlFlow::Continue(()));
+            }
+            Ok(match sender.send((doc_id, doc)) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(_) => ControlFlow::Break(()),
+            })
+        };
+        for doc in documents {
+            let flow = match &splitter {
+                Some(splitter) => split_document(doc, splitter, &mut send)?,
+                None => send(doc)?,
+            };
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(table)
+    });
+
+    (receiver, handle)
+}
+
+/// The index of a single document, tagged with its document id.
+type NumberedIndex = (usize, DocumentIndex);
+
+/// Start `options.indexing_threads` threads that tokenize texts and convert
+/// them into in-memory indexes. Most documents arrive already loaded into
+/// memory, but very large files are streamed from disk by these threads, a
+/// chunk at a time, and their indexes may be written straight to temporary
+/// files in `tmp_dir` (see `index_document`).
+///
+/// `texts` is the stream of numbered documents from the document splitting
+/// thread. The threads take documents from it one at a time, so the indexes
+/// come out tagged with their document ids, but not necessarily in order.
+///
+/// Each index is charged against `budget` as soon as it's built. If the budget
+/// is used up, and the index would arrive at the in-memory merge thread before
+/// its turn, the thread holds on to it until there's room (see
+/// `MemoryBudget::wait_turn`).
+///
+/// This returns a pair of values: a receiver, the sequence of in-memory
+/// indexes; and a `JoinHandle` for each thread, which can be used to wait for
+/// it to exit and to get the `io::Error` if reading a streamed document
+/// failed.
+fn start_file_indexing_threads(
+    texts: mpsc::Receiver<(usize, Document)>,
+    options: IndexOptions,
+    tmp_dir: TmpDir,
+    budget: Arc<MemoryBudget>,
+    progress: Progress,
+    cancel: Cancel,
+) -> (mpsc::Receiver<NumberedIndex>, Vec<thread::JoinHandle<io::Result<()>>>) {
+    let (sender, receiver) = mpsc::sync_channel(32);
+
+    // All the threads share one receiver. Each one holds the lock only long
+    // enough to take the next document.
+    let texts = Arc::new(Mutex::new(texts));
+    let handles = (0..options.indexing_threads.max(1))
+        .map(|_| {
+            let texts = texts.clone();
+            let options = options.clone();
+            let tmp_dir = tmp_dir.clone();
+            let sender = sender.clone();
+            let budget = budget.clone();
+            let progress = progress.clone();
+            let cancel = cancel.clone();
+            spawn_stage(cancel.clone(), move || {
+                while !cancel.is_cancelled() {
+                    let next = texts.lock().unwrap().recv();
+                    let Ok((doc_id, doc)) = next else { break };
+                    let index = index_document(doc_id, doc, &options, &tmp_dir, &progress)?;
+                    if let DocumentIndex::Memory(index) = &index {
+                        budget.charge(index.heap_size());
+                    }
+                    budget.wait_turn(doc_id, &cancel);
+                    if sender.send((doc_id, index)).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    (receiver, handles)
+}
+
 /// Start a thread that merges in-memory indexes.
 ///
-/// `file_indexes` receives a stream of indexes from the file indexing thread.
+/// `file_indexes` receives a stream of indexes from the file indexing threads.
 /// These indexes typically vary a lot in size, since the input documents will
 /// typically be all different sizes.
 ///
+/// Since there are several indexing threads, the indexes can arrive out of
+/// order. To keep the merged indexes sorted by document id (which
+/// `InMemoryIndex::merge` requires), this thread holds on to any index that
+/// arrives early until all the documents before it have been merged. It tells
+/// `budget` which document it's waiting for, so that indexes that would
+/// arrive early can be held back while memory is short.
+///
 /// The thread created by this function merges those indexes into "large"
-/// indexes and passes these large indexes on to a new channel.
+/// indexes and passes these large indexes on to a new channel. An index is
+/// "large" when it reaches `flush_size` bytes, the same rule
+/// `run_single_threaded` uses, so the output is the same no matter how many
+/// indexing threads there are. Each large index is sent along with the number
+/// of documents merged so far, counting from `first_doc_id`, the first
+/// document to expect. A document whose index is already in a file is passed
+/// along in its turn, after whatever has been merged before it.
 ///
 /// This returns a pair: a receiver, the sequence of large indexes produced by
 /// merging the input indexes; and a `JoinHandle` that can be used to wait for
 /// this thread to exit. This stage of the pipeline is infallible (it performs
 /// no I/O).
 fn start_in_memory_merge_thread(
-    file_indexes: mpsc::Receiver<InMemoryIndex>,
-) -> (mpsc::Receiver<InMemoryIndex>, thread::JoinHandle<()>)
+    file_indexes: mpsc::Receiver<NumberedIndex>,
+    first_doc_id: usize,
+    flush_size: usize,
+    budget: Arc<MemoryBudget>,
+    cancel: Cancel,
+) -> (mpsc::Receiver<(DocumentIndex, usize)>, thread::JoinHandle<()>)
 {
-    let (sender, receiver) = mpsc::sync_channel(32);
+    // Large indexes are big. While one is being written, there's room for
+    // just one more to wait its turn; after that, this thread waits too.
+    let (sender, receiver) = mpsc::sync_channel(1);
 
     let handle = thread::spawn(move || {
         let mut accumulated_index = InMemoryIndex::new();
-        for fi in file_indexes {
-            accumulated_index.merge(fi);
-            if accumulated_index.is_large() {
-                if sender.send(accumulated_index).is_err() {
-                    return;
+        let mut early_arrivals = BTreeMap::new();
+        let mut next_doc_id = first_doc_id;
+        for (doc_id, fi) in file_indexes {
+            if cancel.is_cancelled() {
+                return;
+            }
+            early_arrivals.insert(doc_id, fi);
+            while let Some(fi) = early_arrivals.remove(&next_doc_id) {
+                let fi = match fi {
+                    DocumentIndex::Memory(fi) => fi,
+                    DocumentIndex::File(file) => {
+                        if !accumulated_index.is_empty() {
+                            let earlier = std::mem::take(&mut accumulated_index);
+                            if sender.send((DocumentIndex::Memory(earlier), next_doc_id)).is_err() {
+                                return;
+                            }
+                        }
+                        next_doc_id += 1;
+                        if sender.send((DocumentIndex::File(file), next_doc_id)).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                next_doc_id += 1;
+
+                // Merging usually saves memory, since terms that are in both
+                // indexes are stored only once afterwards.
+                let before = accumulated_index.heap_size() + fi.heap_size();
+                accumulated_index.merge(fi);
+                budget.release(before - accumulated_index.heap_size());
+
+                if accumulated_index.heap_size() >= flush_size {
+                    let full_index = std::mem::take(&mut accumulated_index);
+                    if sender.send((DocumentIndex::Memory(full_index), next_doc_id)).is_err() {
+                        return;
+                    }
                 }
-                accumulated_index = InMemoryIndex::new();
             }
+            budget.advance(next_doc_id);
         }
-        if !accumulated_index.is_empty() {
-            let _ = sender.send(accumulated_index);
+
+        // If anything is left in `early_arrivals`, then some document before
+        // it was never indexed. An indexing thread must have failed, and it
+        // will report the error; there's no point saving what we have.
+        if !accumulated_index.is_empty() && early_arrivals.is_empty() && !cancel.is_cancelled() {
+            let _ = sender.send((DocumentIndex::Memory(accumulated_index), next_doc_id));
         }
     });
 
@@ -162,24 +604,41 @@ fn start_in_memory_merge_thread(
 
 /// Start a thread that saves large indexes to temporary files.
 ///
-/// This thread generates a meaningless unique filename for each index in
-/// `big_indexes`, saves the data, and passes the filename on to a new channel.
+/// This thread generates a meaningless unique filename in `tmp_dir` for each
+/// index in `big_indexes`, saves the data, and passes the filename on to a new
+/// channel, along with the document count that came with the index. Indexes
+/// that are already in files are passed along as they are.
+///
+/// Once an index is saved, its memory is released back to `budget`.
 ///
 /// This returns a pair: a receiver that receives the filenames; and a
 /// `JoinHandle` that can be used to wait for this thread to exit and receive
 /// any I/O errors it encountered.
 fn start_index_writer_thread(
-    big_indexes: mpsc::Receiver<InMemoryIndex>,
-    output_dir: &Path,
-) -> (mpsc::Receiver<PathBuf>, thread::JoinHandle<io::Result<()>>)
+    big_indexes: mpsc::Receiver<(DocumentIndex, usize)>,
+    tmp_dir: TmpDir,
+    budget: Arc<MemoryBudget>,
+    progress: Progress,
+    cancel: Cancel,
+) -> (mpsc::Receiver<(PathBuf, usize)>, thread::JoinHandle<io::Result<()>>)
 {
     let (sender, receiver) = mpsc::sync_channel(32);
 
-    let mut tmp_dir = TmpDir::new(output_dir);
-    let handle = thread::spawn(move || {
-        for index in big_indexes {
-            let file = write_index_to_tmp_file(index, &mut tmp_dir)?;
-            if sender.send(file).is_err() {
+    let handle = spawn_stage(cancel.clone(), move || {
+        for (index, documents) in big_indexes {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let file = match index {
+                DocumentIndex::Memory(index) => {
+                    let size = index.heap_size();
+                    let file = write_index_to_tmp_file(index, &tmp_dir, &progress)?;
+                    budget.release(size);
+                    file
+                }
+                DocumentIndex::File(file) => file,
+            };
+            if sender.send((file, documents)).is_err() {
                 break;
             }
         }
@@ -189,114 +648,550 @@ fn start_index_writer_thread(
     (receiver, handle)
 }
 
-/// Given a sequence of filenames of index data files, merge all the files
-/// into a single index data file.
+/// Given a sequence of filenames of index data files, add them to `merge` as
+/// they arrive. Returns the `FileMerge`, ready to be finished once the
+/// document table is complete.
 fn merge_index_files(
-    files: mpsc::Receiver<PathBuf>,
-    output_dir: &Path,
-) -> io::Result<()>
+    files: mpsc::Receiver<(PathBuf, usize)>,
+    mut merge: FileMerge,
+) -> io::Result<FileMerge>
 {
-    let mut merge = FileMerge::new(output_dir);
-    for file in files {
-        merge.add_file(file)?;
+    for (file, documents) in files {
+        merge.add_file(file, documents)?;
     }
-    merge.finish()
+    Ok(merge)
 }
 
 /// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
+/// storing it in the files named by `paths`.
+///
+/// On success this does exactly the same thing as `run_single_threaded`, down
+/// to the bytes of the index: both write an accumulated index to disk by the
+/// same rule (see `memory::flush_size`), so they write the same temporary
+/// files, which are merged the same way. This is just faster, since it uses
+/// multiple CPUs and keeps them busy while I/O is happening.
 ///
-/// On success this does exactly the same thing as `run_single_threaded`, but
-/// faster since it uses multiple CPUs and keeps them busy while I/O is
-/// happening.
+/// If any stage fails, or `cancel` is set from outside, every stage stops as
+/// soon as it notices, and no index is saved.
 fn run_pipeline(
     documents: Vec<PathBuf>,
-    output_dir: PathBuf,
+    paths: &IndexPaths,
+    options: &IndexOptions,
+    checkpoint: Checkpoint,
+    table: DocumentTable,
+    progress: &Progress,
+    cancel: &Cancel,
 ) -> io::Result<()> {
-    // Launch all five stages of the pipeline.
-    let (texts,   h1) = start_file_reader_thread(documents);
-    let (pints,   h2) = start_file_indexing_thread(texts);
-    let (gallons, h3) = start_in_memory_merge_thread(pints);
-    let (files,   h4) = start_index_writer_thread(gallons, &output_dir);
-    let result = merge_index_files(files, &output_dir);
+    // All the stages that hold in-memory indexes share a memory budget.
+    let budget = Arc::new(MemoryBudget::new(options.memory_limit, checkpoint.documents));
+
+    // The stages that write temporary files share a `TmpDir`, which deletes
+    // any that are left over if something goes wrong.
+    let tmp_dir = TmpDir::new(paths.tmp_dir(), paths.name())?;
+
+    // Skip the documents that are already in the index we're adding to, or
+    // (when resuming) in temporary files.
+    let first_doc_id = checkpoint.documents;
+
+    // Temporary files are merged as they arrive, starting from `checkpoint`.
+    let merge = FileMerge::new(
+        paths.clone(),
+        tmp_dir.clone(),
+        checkpoint,
+        table.deleted().clone(),
+        options.merge,
+        progress.clone(),
+        cancel.clone(),
+    );
+
+    // Launch all seven stages of the pipeline.
+    let (raw,     h1) = start_file_reader_thread(documents, options.mode, progress.clone(), cancel.clone());
+    let (texts,   h2) = start_text_extraction_thread(raw, options.use_fields, cancel.clone());
+    let (slices,  h3) = start_document_splitting_thread(texts, options.splitter.clone(), table, first_doc_id, cancel.clone());
+    let (pints,   h4) = start_file_indexing_threads(slices, options.clone(), tmp_dir.clone(), budget.clone(), progress.clone(), cancel.clone());
+    let (gallons, h5) = start_in_memory_merge_thread(pints, first_doc_id, flush_size(options.memory_limit), budget.clone(), cancel.clone());
+    let (files,   h6) = start_index_writer_thread(gallons, tmp_dir.clone(), budget, progress.clone(), cancel.clone());
+    let result = cancel.on_error(merge_index_files(files, merge));
 
     // Wait for threads to finish, holding on to any errors that they encounter.
     let r1 = h1.join().unwrap();
     h2.join().unwrap();
-    h3.join().unwrap();
-    let r4 = h4.join().unwrap();
+    let table = h3.join().unwrap();
+    let r4: Vec<io::Result<()>> = h4.into_iter().map(|h| h.join().unwrap()).collect();
+    h5.join().unwrap();
+    let r6 = h6.join().unwrap();
 
     // Return the first error encountered, if any.
-    // (As it happens, h2 and h3 can't fail: those threads
+    // (As it happens, h2 and h5 can't fail: those threads
     // are pure in-memory data processing.)
     r1?;
-    r4?;
-    result
+    let table = table?;
+    r4.into_iter().collect::<io::Result<()>>()?;
+    r6?;
+    let merge = result?;
+
+    // Everything else succeeded, so finish merging and publish the index,
+    // along with the names of the documents.
+    let generation = merge.finish(&table)?;
+    progress.event(Event::Finished {
+        path: &paths.manifest(generation),
+        documents: table.len(),
+        peak_tmp_bytes: tmp_dir.peak_usage(),
+    });
+    Ok(())
 }
 
 /// Given some paths, generate the complete list of text files to index. We check
 /// on disk whether the path is the name of a file or a directory; for
-/// directories, all .txt files immediately under the directory are indexed.
-/// Relative paths are fine.
+/// directories, all the files in the directory and its subdirectories are
+/// indexed. Relative paths are fine. Archives (tar, tar.gz and zip files) are listed
+/// here like any other file; their members are expanded later, as they're read.
+/// Likewise in `InputMode::Mail` mode, Maildir directories are listed as-is.
+///
+/// The index's own files, and its temporary files, are left out, in case
+/// it's being saved among the documents (see `IndexPaths::owns`).
 ///
 /// It's an error if any of the `args` is not a valid path to an existing file
 /// or directory.
-fn expand_filename_arguments(args: Vec<String>) -> io::Result<Vec<PathBuf>> {
+fn expand_filename_arguments(
+    args: Vec<String>,
+    mode: InputMode,
+    paths: &IndexPaths,
+) -> io::Result<Vec<PathBuf>> {
     let mut filenames = vec![];
     for arg in args {
         let path = PathBuf::from(arg);
-        if path.metadata()?.is_dir() {
-            for entry in path.read_dir()? {
-                let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    filenames.push(entry.path());
-                }
-            }
-        } else {
+        let is_maildir = mode == InputMode::Mail && mail::is_maildir(&path);
+        if path.metadata().in_file(&path)?.is_dir() && !is_maildir {
+            expand_directory(&path, mode, paths, &mut filenames)?;
+        } else if !paths.owns(&path) {
             filenames.push(path);
         }
     }
     Ok(filenames)
 }
 
-/// Generate an index for a bunch of text files.
-fn run(filenames: Vec<String>, single_threaded: bool) -> io::Result<()> {
-    let output_dir = PathBuf::from(".");
-    let documents = expand_filename_arguments(filenames)?;
+/// Add the files in the directory `dir`, and in its subdirectories, to
+/// `filenames`, for `expand_filename_arguments`. Symbolic links are skipped,
+/// so a link back up the tree can't send this around in circles.
+fn expand_directory(dir: &Path, mode: InputMode, paths: &IndexPaths, filenames: &mut Vec<PathBuf>) -> io::Result<()> {
+    let index_dir = paths.is_index_dir(dir);
+    for entry in dir.read_dir().in_file(dir)? {
+        let entry = entry.in_file(dir)?;
+        if index_dir && paths.is_own_file_name(&entry.file_name()) {
+            continue;
+        }
+        let path = entry.path();
+        let file_type = entry.file_type().in_file(&path)?;
+        if file_type.is_file() || (file_type.is_dir() && mode == InputMode::Mail && mail::is_maildir(&path)) {
+            filenames.push(path);
+        } else if file_type.is_dir() {
+            expand_directory(&path, mode, paths, filenames)?;
+        }
+    }
+    Ok(())
+}
 
-    if single_threaded {
-        run_single_threaded(documents, output_dir)
+/// Find out where to start indexing. If `resume` is true, that's wherever
+/// the last run was interrupted, according to its checkpoint. Otherwise it's
+/// the beginning, and any leftovers from an interrupted run are deleted.
+///
+/// If `add` is true, the new documents are added to the current index, and
+/// this also returns its document table, with any documents from the files in
+/// `documents` marked as deleted, since they're being replaced. Otherwise the
+/// table is empty.
+fn start_or_resume(
+    paths: &IndexPaths,
+    documents: &[PathBuf],
+    options: &IndexOptions,
+    resume: bool,
+    add: bool,
+) -> Result<(Checkpoint, DocumentTable), Error> {
+    let key = options.checkpoint_key();
+    let base = if add {
+        Some(Manifest::load_existing(paths)?)
     } else {
-        run_pipeline(documents, output_dir)
+        None
+    };
+    let table = match &base {
+        Some(manifest) => {
+            // Documents from files that are being indexed again are replaced:
+            // the old versions are deleted, and the new ones added.
+            let mut table = manifest.read_table(paths)?;
+            let names: Vec<String> = documents.iter().map(|path| path.display().to_string()).collect();
+            for doc_id in table.ids_from_any(&names) {
+                table.delete(doc_id);
+            }
+            table
+        }
+        None => DocumentTable::new(),
+    };
+
+    let previous = Checkpoint::load(&paths.checkpoint())?;
+    if resume {
+        let checkpoint = previous.ok_or_else(|| {
+            Error::Usage("can't resume: no interrupted run to resume".to_string())
+        })?;
+        checkpoint.check_resumable(&key, documents, base.map(|manifest| manifest.generation))?;
+        remove_leftover_files(paths, &checkpoint)?;
+        return Ok((checkpoint, table));
     }
+
+    if let Some(previous) = previous {
+        for segment in previous.files() {
+            match fs::remove_file(segment) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(Error::File { path: segment.clone(), position: None, err });
+                }
+                _ => {}
+            }
+        }
+        Checkpoint::remove(&paths.checkpoint())?;
+    }
+    let checkpoint = Checkpoint::new(key, documents, base.as_ref(), table.len());
+    remove_leftover_files(paths, &checkpoint)?;
+    Ok((checkpoint, table))
+}
+
+/// Delete the temporary files for this index that `checkpoint` doesn't list.
+/// A run that crashed, or was killed, can leave them behind: a file is
+/// written before the checkpoint that lists it is saved, and merged files are
+/// deleted afterwards.
+fn remove_leftover_files(paths: &IndexPaths, checkpoint: &Checkpoint) -> io::Result<()> {
+    // Compare names, not paths, in case the temporary directory is spelled
+    // differently this time.
+    let needed: Vec<_> = checkpoint.files().filter_map(|path| path.file_name()).collect();
+    for path in tmp::leftover_files(paths.tmp_dir(), paths.name())? {
+        if !path.file_name().is_some_and(|name| needed.contains(&name)) {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err).in_file(&path),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generate an index for a bunch of text files, saving it in the files named
+/// by `paths`. The run stops early if `interrupt` is set.
+fn run(
+    documents: Vec<PathBuf>,
+    paths: IndexPaths,
+    options: IndexOptions,
+    run_options: RunOptions,
+    interrupt: &Cancel,
+) -> Result<(), Error> {
+    fs::create_dir_all(paths.dir()).in_file(paths.dir())?;
+    fs::create_dir_all(paths.tmp_dir()).in_file(paths.tmp_dir())?;
+    let (checkpoint, table) =
+        start_or_resume(&paths, &documents, &options, run_options.resume, run_options.add)?;
+    let base_size = checkpoint.published.iter().map(|path| input_size(path)).sum();
+    check_space(&paths, &documents, base_size, run_options.space_check)?;
+    let total_bytes = documents.iter().map(|path| input_size(path)).sum();
+    let progress = Progress::new(run_options.progress_mode, total_bytes);
+    let cancel = interrupt.child();
+
+    let result = if run_options.single_threaded {
+        run_single_threaded(documents, &paths, &options, checkpoint, table, &progress, &cancel)
+    } else {
+        run_pipeline(documents, &paths, &options, checkpoint, table, &progress, &cancel)
+    };
+    if result.is_err() {
+        // Get the progress bar out of the way of the error message.
+        progress.clear();
+    }
+    Ok(result?)
 }
 
 fn main() {
-    let mut single_threaded = false;
-    let mut filenames = vec![];
+    let result = cli::run_command();
+    match result {
+        // Output piped into something like `head`, which stopped reading
+        // once it had enough. That's not worth complaining about.
+        Err(Error::Io(err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(err) => {
+            eprintln!("error: {err}");
+            process::exit(err.exit_status());
+        }
+        Ok(()) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
 
-    {
-        let mut ap = ArgumentParser::new();
-        ap.set_description("Make an inverted index for searching documents.");
-        ap.refer(&mut single_threaded)
-            .add_option(
-                &["-1", "--single-threaded"],
-                StoreTrue,
-                "Do all the work on a single thread.",
-            );
-        ap.refer(&mut filenames)
-            .add_argument(
-                "filenames",
-                Collect,
-                "\
-                    Names of files/directories to index. \
-                    For directories, all .txt files immediately \
-                    under the directory are indexed.",
-            );
-        ap.parse_args_or_exit();
+    use super::*;
+    use crate::merge::{InputPolicy, DEFAULT_FAN_IN, DEFAULT_MERGE_THREADS};
+    use crate::memory::DEFAULT_MEMORY_LIMIT;
+    use crate::paths::DEFAULT_INDEX_NAME;
+    use crate::search::{parse_query, Index};
+
+    /// A new, empty directory for a test's files. Tests in other modules
+    /// use this and the helpers below too, so `name` must be unique across
+    /// the crate.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fingertips-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub(crate) fn options(indexing_threads: usize, memory_limit: usize) -> IndexOptions {
+        IndexOptions {
+            mode: InputMode::Files,
+            use_fields: false,
+            splitter: None,
+            indexing_threads,
+            memory_limit,
+            merge: MergeOptions {
+                fan_in: DEFAULT_FAN_IN,
+                threads: DEFAULT_MERGE_THREADS,
+                compact: false,
+                inputs: InputPolicy::Delete,
+            },
+        }
+    }
+
+    /// Index `documents` into the index in `output_dir`, on one thread or
+    /// with the pipeline. If `add` is true, they're added to the index that's
+    /// already there; otherwise it's built from scratch.
+    pub(crate) fn build(documents: &[PathBuf], output_dir: &Path, single_threaded: bool, options: &IndexOptions, add: bool) {
+        fs::create_dir_all(output_dir).unwrap();
+        let paths = IndexPaths::new(output_dir.to_path_buf(), DEFAULT_INDEX_NAME.to_string(), output_dir.to_path_buf());
+        let (checkpoint, table) = start_or_resume(&paths, documents, options, false, add).unwrap();
+        let progress = Progress::new(ProgressMode::Quiet, 0);
+        let cancel = Cancel::new();
+        if single_threaded {
+            run_single_threaded(documents.to_vec(), &paths, options, checkpoint, table, &progress, &cancel).unwrap();
+        } else {
+            run_pipeline(documents.to_vec(), &paths, options, checkpoint, table, &progress, &cancel).unwrap();
+        }
+    }
+
+    /// Write `count` small documents into `dir`, sharing some words so that
+    /// the index has terms with many hits.
+    pub(crate) fn write_documents(dir: &Path, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("doc{i:03}.txt"));
+                let text = format!("common words in document {i}\n{}", "padding ".repeat(i % 7));
+                fs::write(&path, text).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    /// Every file in `dir`, by name, with its contents.
+    fn read_output(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Search the index in `dir` for each of `terms`, and return the names of
+    /// the documents that match, with where the term is in each.
+    pub(crate) fn search_all(dir: &Path, terms: &[&str]) -> Vec<Vec<(String, Vec<u32>)>> {
+        let paths = IndexPaths::new(dir.to_path_buf(), DEFAULT_INDEX_NAME.to_string(), dir.to_path_buf());
+        let index = Index::open(&paths).unwrap();
+        terms
+            .iter()
+            .map(|term| {
+                let matches = index.search(term).unwrap();
+                matches.into_iter().map(|m| (index.table().name(m.doc_id).to_string(), m.offsets)).collect()
+            })
+            .collect()
     }
 
-    if let Err(err) = run(filenames, single_threaded) {
-        eprintln!("error: {err}");
+    #[test]
+    fn worker_pool_matches_single_thread() {
+        let dir = test_dir("worker-pool");
+        let input = dir.join("input");
+        fs::create_dir(&input).unwrap();
+        let documents = write_documents(&input, 100);
+
+        let single = dir.join("single");
+        build(&documents, &single, true, &options(1, DEFAULT_MEMORY_LIMIT), false);
+
+        let pooled = dir.join("pooled");
+        build(&documents, &pooled, false, &options(4, DEFAULT_MEMORY_LIMIT), false);
+
+        let expected = read_output(&single);
+        assert!(!expected.is_empty());
+        assert_eq!(read_output(&pooled), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn small_memory_limit_flushes() {
+        // Indexes arriving out of order are merged in order, and written out
+        // each time the accumulated index reaches the flush size.
+        let indexes: Vec<InMemoryIndex> = (0..20)
+            .map(|doc_id| InMemoryIndex::from_single_document(doc_id, format!("word{doc_id} shared")))
+            .collect();
+        let flush_size = indexes[0].heap_size() * 3;
+        let budget = Arc::new(MemoryBudget::new(flush_size * 2, 0));
+        let (sender, receiver) = mpsc::channel();
+        let (large, handle) = start_in_memory_merge_thread(receiver, 0, flush_size, budget.clone(), Cancel::new());
+        for (doc_id, index) in indexes.into_iter().enumerate().rev() {
+            budget.charge(index.heap_size());
+            sender.send((doc_id, DocumentIndex::Memory(index))).unwrap();
+        }
+        drop(sender);
+
+        let large: Vec<InMemoryIndex> = large
+            .into_iter()
+            .map(|(index, _)| match index {
+                DocumentIndex::Memory(index) => index,
+                DocumentIndex::File(_) => panic!("nothing was written to a file"),
+            })
+            .collect();
+        handle.join().unwrap();
+        assert!(large.len() > 1);
+        let (_last, full) = large.split_last().unwrap();
+        assert!(full.iter().all(|index| index.heap_size() >= flush_size));
+        assert_eq!(large.iter().map(|index| index.map["shared"].len()).sum::<usize>(), 20);
+
+        // The whole pipeline, with a memory limit so small that it's always
+        // used up, gives the same index as a single thread.
+        let dir = test_dir("memory-limit");
+        let input = dir.join("input");
+        fs::create_dir(&input).unwrap();
+        let documents = write_documents(&input, 100);
+
+        let single = dir.join("single");
+        build(&documents, &single, true, &options(1, 4096), false);
+
+        let pooled = dir.join("pooled");
+        build(&documents, &pooled, false, &options(4, 4096), false);
+
+        assert_eq!(read_output(&pooled), read_output(&single));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_matches_building_from_scratch() {
+        let dir = test_dir("add");
+        let input = dir.join("input");
+        fs::create_dir(&input).unwrap();
+        let documents = write_documents(&input, 30);
+        let options = options(2, DEFAULT_MEMORY_LIMIT);
+
+        let whole = dir.join("whole");
+        build(&documents, &whole, false, &options, false);
+
+        let added = dir.join("added");
+        build(&documents[..10], &added, false, &options, false);
+        build(&documents[10..25], &added, true, &options, true);
+        build(&documents[25..], &added, false, &options, true);
+
+        let terms = ["common", "document", "padding", "0", "9", "10", "24", "25", "29"];
+        let expected = search_all(&whole, &terms);
+        assert_eq!(expected[0].len(), 30);
+        assert_eq!(search_all(&added, &terms), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_publishes_new_documents_then_compacts() {
+        let dir = test_dir("compact-after-add");
+        let input = dir.join("input");
+        fs::create_dir(&input).unwrap();
+        let documents = write_documents(&input, 20);
+        let mut options = options(2, DEFAULT_MEMORY_LIMIT);
+        options.merge.fan_in = 2;
+
+        let output = dir.join("output");
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output.clone());
+        build(&documents[..10], &output, false, &options, false);
+        build(&documents[10..], &output, false, &options, true);
+
+        // Generation 2 has the new documents in a segment of their own, next
+        // to the old one; generation 3 has the two merged.
+        let manifest = Manifest::load_existing(&paths).unwrap();
+        assert_eq!(manifest.generation, 3);
+        assert_eq!(manifest.segments.len(), 1);
+        assert_eq!(manifest.segments[0].0, 1);
+        let previous = fs::read_to_string(paths.manifest(2)).unwrap();
+        assert_eq!(previous.lines().filter(|line| line.starts_with("segment 0 ")).count(), 2);
+
+        let whole = dir.join("whole");
+        build(&documents, &whole, false, &options, false);
+        let terms = ["common", "document", "0", "9", "10", "19"];
+        assert_eq!(search_all(&output, &terms), search_all(&whole, &terms));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_files_are_not_documents() {
+        // The index is kept in the same directory as the documents.
+        let dir = test_dir("own-files");
+        let paths = IndexPaths::new(dir.clone(), DEFAULT_INDEX_NAME.to_string(), dir.clone());
+        let own = [
+            "index.current",
+            "index.lock",
+            "index.checkpoint",
+            "index.12.manifest",
+            "index.12.docs",
+            "index.12.del",
+            "index.s3.dat",
+            "index.tmp0000001f.dat",
+        ];
+        let documents = ["index.html", "index.txt", "index.s3.txt", "index.x.docs", "index.tmp1.dat", "notes.txt"];
+        for filename in own.iter().chain(&documents) {
+            fs::write(dir.join(filename), "text").unwrap();
+        }
+
+        let mut found: Vec<String> = expand_filename_arguments(vec![dir.display().to_string()], InputMode::Files, &paths)
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        found.sort();
+        let mut expected = documents.to_vec();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // The same goes for files named on their own.
+        let named = |filename: &str| vec![dir.join(filename).display().to_string()];
+        assert!(expand_filename_arguments(named("index.s3.dat"), InputMode::Files, &paths).unwrap().is_empty());
+        assert_eq!(expand_filename_arguments(named("index.html"), InputMode::Files, &paths).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn field_query() {
+        let dir = test_dir("field-query");
+        let page = dir.join("page.html");
+        fs::write(&page, "<html><title>Rust</title><body>Oxidation of iron</body></html>").unwrap();
+        let other = dir.join("other.html");
+        fs::write(&other, "<html><title>Iron</title><body>Rust never sleeps</body></html>").unwrap();
+        let output = dir.join("index");
+        let options = IndexOptions { use_fields: true, ..options(2, DEFAULT_MEMORY_LIMIT) };
+        build(&[page, other], &output, true, &options, false);
+
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output);
+        let index = Index::open(&paths).unwrap();
+        let names = |query: &str| -> Vec<String> {
+            let term = parse_query(query).unwrap();
+            let matches = index.search(&term).unwrap();
+            matches.iter().map(|m| index.table().name(m.doc_id).to_string()).collect()
+        };
+        assert_eq!(names("title:rust"), vec![dir.join("page.html").display().to_string()]);
+        assert_eq!(names("title:iron"), vec![dir.join("other.html").display().to_string()]);
+        // Titles aren't part of the body text.
+        assert_eq!(names("rust"), vec![dir.join("other.html").display().to_string()]);
+        assert_eq!(names("iron"), vec![dir.join("page.html").display().to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
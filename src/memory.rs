@@ -0,0 +1,133 @@
+//! Keeping track of how much memory the indexer is using.
+//!
+//! The pipeline has in-memory indexes in several places at once: the ones
+//! being built by the indexing threads, the ones waiting in channels between
+//! stages, the ones that arrived at the in-memory merge thread ahead of their
+//! turn, the one being accumulated there, and the one being written to disk.
+//!
+//! When to write the accumulated index to disk doesn't depend on any of the
+//! others, only on its own size (see `flush_size`). That way the temporary
+//! files, and so the finished index, are the same however many threads there
+//! are, and whichever of them happens to be quicker. Instead, the rest of the
+//! pipeline is kept in check by a `MemoryBudget`, shared by all the stages:
+//! while it's used up, indexing threads hold on to indexes that would arrive
+//! early, rather than piling them up at the merge thread.
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::cancel::Cancel;
+
+use crate::error::Error;
+
+/// The default memory limit, if none is specified on the command line.
+pub const DEFAULT_MEMORY_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// How big the index being accumulated in memory gets, with a memory limit of
+/// `memory_limit`, before it's written to disk. It's half the limit, leaving
+/// the other half for the index before it, which is being written, and the
+/// documents on their way in.
+pub fn flush_size(memory_limit: usize) -> usize {
+    memory_limit / 2
+}
+
+/// How often a thread waiting for its turn checks whether it should give up.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A limit on the total approximate size of all in-memory indexes, and a
+/// count of how much of it is currently in use.
+///
+/// Stages `charge` the budget when they create index data, and `release` it
+/// when that data is freed. The counts are approximate: see
+/// `InMemoryIndex::heap_size`.
+pub struct MemoryBudget {
+    limit: usize,
+    state: Mutex<BudgetState>,
+    changed: Condvar,
+}
+
+struct BudgetState {
+    in_use: usize,
+
+    /// The document the in-memory merge thread is waiting for.
+    next_doc_id: usize,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes, for a pipeline whose first document is
+    /// `first_doc_id`.
+    pub fn new(limit: usize, first_doc_id: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            state: Mutex::new(BudgetState { in_use: 0, next_doc_id: first_doc_id }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Record that `nbytes` more bytes are in use.
+    pub fn charge(&self, nbytes: usize) {
+        self.state.lock().unwrap().in_use += nbytes;
+    }
+
+    /// Record that `nbytes` bytes have been freed.
+    pub fn release(&self, nbytes: usize) {
+        self.state.lock().unwrap().in_use -= nbytes;
+        self.changed.notify_all();
+    }
+
+    /// Record that the in-memory merge thread is now waiting for document
+    /// `doc_id`.
+    pub fn advance(&self, doc_id: usize) {
+        self.state.lock().unwrap().next_doc_id = doc_id;
+        self.changed.notify_all();
+    }
+
+    /// Wait until it's all right to send the index of document `doc_id` to
+    /// the in-memory merge thread: either there's room for it in the budget,
+    /// or it's the one the merge thread is waiting for. The merge thread can
+    /// always make progress, and free up memory, so this never waits forever,
+    /// unless `cancel` is set, and then it stops waiting.
+    pub fn wait_turn(&self, doc_id: usize, cancel: &Cancel) {
+        let mut state = self.state.lock().unwrap();
+        while state.in_use > self.limit && doc_id != state.next_doc_id && !cancel.is_cancelled() {
+            state = self.changed.wait_timeout(state, CANCEL_POLL_INTERVAL).unwrap().0;
+        }
+    }
+}
+
+/// Parse a size like `512M` or `2G` (binary units; the suffix is optional and
+/// case-insensitive) into a number of bytes.
+pub fn parse_size(s: &str) -> Result<usize, Error> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| Error::Usage(format!("invalid size: {s:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 2G ").unwrap(), 2 << 30);
+    }
+
+    #[test]
+    fn bad_sizes() {
+        for s in ["", "G", "1.5G", "-1", "12T", "lots", "99999999999999999999G"] {
+            assert!(matches!(parse_size(s), Err(Error::Usage(_))), "{s:?}");
+        }
+    }
+}
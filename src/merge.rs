@@ -1,129 +1,886 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
 use std::fs::{self, File};
 use std::io::{self, BufWriter};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::cancel::Cancel;
+use crate::checkpoint::Checkpoint;
+use crate::docs::DocumentTable;
+use crate::index::HIT_HEADER_SIZE;
+use crate::progress::{Event, Progress};
 use crate::read::IndexFileReader;
+use crate::tombstones::Tombstones;
+use crate::paths::IndexPaths;
+use crate::publish::{publish, Manifest};
 use crate::tmp::TmpDir;
 use crate::write::IndexFileWriter;
 
+/// Merges temporary index files, as they're produced, into the segments of an
+/// index.
+///
+/// Files are kept in stacks, one per level. New files go on level 0. When a
+/// level has `fan_in` files, they're merged, and the result goes on the next
+/// level up. The merging itself happens in the background, on a pool of
+/// threads, so that indexing can carry on in the meantime.
+///
+/// The stacks outlive the run: when it's done, whatever files are in them are
+/// published as the index's segments, each with its level (see `publish`).
+/// When documents are added to an existing index, its segments are set aside
+/// while the new files are merged, and published along with them, so that
+/// the new documents can be searched as soon as possible. Then the index is
+/// compacted: its segments are loaded back into the stacks, and merged by the
+/// same rules, on the same pool of threads, and the result is published as
+/// another generation. The run waits for that before it's done, so a run that
+/// adds to an index takes as long as it did when the merges came first; only
+/// readers get the new documents sooner. So an index is never rewritten in
+/// full just to add a few documents, and each document is copied only about
+/// once per level.
 pub struct FileMerge {
-    output_dir: PathBuf,
+    paths: IndexPaths,
     tmp_dir: TmpDir,
-    stacks: Vec<Vec<PathBuf>>,
+
+    /// The stacks of files, oldest first.
+    stacks: Vec<Vec<Segment>>,
+
+    /// The segments of the index being added to, oldest first, each with its
+    /// level. They're older than anything in the stacks, and aren't merged
+    /// until the new documents are published (see `compact`).
+    base: Vec<(usize, PathBuf)>,
+
+    /// The number of documents in all the files added so far. This, along
+    /// with `stacks`, is saved to disk every time it changes, so that an
+    /// interrupted run can be resumed.
+    checkpoint: Checkpoint,
+
+    options: MergeOptions,
+    pool: MergePool,
+    cancel: Cancel,
+
+    /// True once the index is complete.
+    finished: bool,
 }
 
-// How many files to merge at a time, at most.
-const NSTREAMS: usize = 8;
+/// Settings that control how temporary files are merged.
+#[derive(Clone, Copy)]
+pub struct MergeOptions {
+    /// How many files to merge at a time, at most. Must be at least 2.
+    pub fan_in: usize,
 
-const MERGED_FILENAME: &str = "index.dat";
+    /// How many merges to run at once, in the background. If this is 0,
+    /// merges are done right away, on the thread that adds the files.
+    pub threads: usize,
+
+    /// If true, everything is merged into a single segment at the end.
+    pub compact: bool,
+
+    /// What to do with temporary files once they've been merged.
+    pub inputs: InputPolicy,
+}
+
+/// What a merge does with its input files, once their contents are safely in
+/// the output file.
+///
+/// Reading an index file never changes it (see `IndexFileReader`), so
+/// deleting inputs is entirely up to the merge, and this is the only place it
+/// happens. Whatever the policy, a merge never deletes the segments of the
+/// index being added to, which still belong to its current generation, or
+/// files that are listed in the checkpoint, which an interrupted run needs to
+/// resume; `publish` cleans up old segments once no generation uses them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InputPolicy {
+    /// Delete temporary files as soon as they've been merged, to save disk
+    /// space.
+    Delete,
+
+    /// Leave temporary files in the temporary directory, so that each step
+    /// of the merge can be looked at afterwards with `fingertips dump`. The
+    /// next run on the same index deletes them (see `tmp::leftover_files`).
+    Keep,
+}
+
+/// How many files to merge at a time, unless told otherwise.
+///
+/// Merging more files at once means fewer passes over the data, but more
+/// files open at once, and more disk seeking back and forth between them.
+pub const DEFAULT_FAN_IN: usize = 8;
+
+/// How many merges to run at once, unless told otherwise. Merging is mostly
+/// I/O, so there's not much to gain from running lots of them.
+pub const DEFAULT_MERGE_THREADS: usize = 2;
+
+/// An entry in one of `FileMerge`'s stacks.
+enum Segment {
+    /// A file that's ready to be merged.
+    Ready(PathBuf),
+
+    /// A merge that's still running in the background. `inputs` are the
+    /// files being merged; once the merge is done, they're disposed of
+    /// according to the `InputPolicy`.
+    Merging {
+        inputs: Vec<PathBuf>,
+        result: mpsc::Receiver<io::Result<PathBuf>>,
+    },
+}
 
 impl FileMerge {
-    pub fn new(output_dir: &Path) -> FileMerge {
+    /// Create a `FileMerge` that picks up where `checkpoint` left off. (For a
+    /// fresh start, pass a new checkpoint.) Documents in `deleted` are purged
+    /// from the index as it's merged.
+    pub fn new(
+        paths: IndexPaths,
+        tmp_dir: TmpDir,
+        mut checkpoint: Checkpoint,
+        deleted: Tombstones,
+        options: MergeOptions,
+        progress: Progress,
+        cancel: Cancel,
+    ) -> FileMerge {
+        assert!(options.fan_in >= 2, "can't merge fewer than 2 files at a time");
+        // Temporary files left over from an interrupted run count toward
+        // temporary disk usage.
+        let (base, segments): (Vec<_>, Vec<_>) = mem::take(&mut checkpoint.segments)
+            .into_iter()
+            .partition(|(_, file)| checkpoint.published.contains(file));
+        for (_, file) in &segments {
+            tmp_dir.add_usage(fs::metadata(file).map_or(0, |m| m.len()));
+        }
+        let stacks = load_stacks(segments);
+        let deleted = Arc::new(deleted);
+        let pool = MergePool::new(options.threads, &deleted, &tmp_dir, &progress, &cancel);
         FileMerge {
-            output_dir: output_dir.to_owned(),
-            tmp_dir: TmpDir::new(output_dir),
-            stacks: vec![],
+            paths,
+            tmp_dir,
+            stacks,
+            base,
+            checkpoint,
+            options,
+            pool,
+            cancel,
+            finished: false,
         }
     }
 
-    pub fn add_file(&mut self, mut file: PathBuf) -> io::Result<()> {
-        let mut level = 0;
+    /// Add a temporary index file to be merged. `documents` is the number of
+    /// documents in all the files added so far, including this one.
+    pub fn add_file(&mut self, file: PathBuf, documents: usize) -> io::Result<()> {
+        if self.stacks.is_empty() {
+            self.stacks.push(vec![]);
+        }
+        self.stacks[0].push(Segment::Ready(file));
+
+        // Start any merges that are ready to go. Merges that finish can make
+        // others ready, so keep going until nothing changes.
+        let mut merged = vec![];
         loop {
-            if level == self.stacks.len() {
-                self.stacks.push(vec![]);
+            self.start_merges();
+            let finished = self.collect_finished_merges(false)?;
+            if finished.is_empty() {
+                break;
             }
-            self.stacks[level].push(file);
-            if self.stacks[level].len() < NSTREAMS {
+            merged.extend(finished);
+        }
+
+        self.checkpoint.documents = documents;
+        self.save_checkpoint()?;
+        self.dispose_of(&merged, &[])
+    }
+
+    /// Finish merging by the usual rules, then publish the files in the
+    /// stacks, after the segments of the index being added to, if any, as the
+    /// segments of a new generation of the index, along with the document
+    /// table `table`. Then compact it (see `compact`), waiting for that to
+    /// finish too. Returns the generation that's current afterwards.
+    ///
+    /// If the `compact` option is set, all the files are merged into one
+    /// first. When adding to an existing index, its segments still belong to
+    /// the previous generation, so even those that are merged are left in
+    /// place.
+    pub fn finish(mut self, table: &DocumentTable) -> io::Result<u64> {
+        // Merges that finish can make others ready, so keep going until every
+        // level has fewer than `fan_in` files.
+        loop {
+            self.start_merges();
+            let merged = self.collect_finished_merges(true)?;
+            if merged.is_empty() {
                 break;
             }
-            let (filename, out) = self.tmp_dir.create()?;
-            let mut to_merge = vec![];
-            mem::swap(&mut self.stacks[level], &mut to_merge);
-            merge_streams(to_merge, out)?;
-            file = filename;
-            level += 1;
+            self.save_checkpoint()?;
+            self.dispose_of(&merged, &[])?;
         }
-        Ok(())
+
+        // The files in the checkpoint are kept until the very end, in case
+        // we're interrupted; other files are disposed of as soon as they're
+        // merged.
+        let checkpointed: Vec<PathBuf> = self.checkpoint.files().cloned().collect();
+
+        let mut segments = mem::take(&mut self.base);
+        segments.extend(self.take_segments());
+
+        if self.options.compact && !segments.is_empty() {
+            // Merge everything, oldest first, in as many passes as it takes.
+            // The merges in each pass run in parallel. They combine files
+            // from every level, so count them as being on the highest one.
+            let mut level = segments[0].0;
+            let mut files: Vec<PathBuf> = segments.into_iter().map(|(_, file)| file).collect();
+            while files.len() > 1 {
+                files = self.merge_pass(files, level, &checkpointed)?;
+                level += 1;
+            }
+            // A segment that's already on its own may still have deleted
+            // documents in it, if it's from the index being added to.
+            if !self.pool.deleted.is_empty() && self.checkpoint.published.contains(&files[0]) {
+                files = vec![receive(&self.pool.start(files, level))?];
+                level += 1;
+            }
+            segments = vec![(level, files.pop().expect("merging leaves one file"))];
+        }
+
+        self.cancel.check()?;
+        if segments.is_empty() {
+            return Err(io::Error::other(
+                "no documents were parsed or none contained any words",
+            ));
+        }
+        let generation = publish(&self.paths, &segments, table)?;
+        Checkpoint::remove(&self.paths.checkpoint())?;
+        // The ones that were published have been moved into place by now;
+        // the rest were merged into them.
+        self.dispose_of(&checkpointed, &[])?;
+        let generation = self.compact(generation, table)?;
+        self.finished = true;
+        Ok(generation)
+    }
+
+    /// Compact the index just published as `generation`: load its segments
+    /// into the stacks, and merge them by the usual rules, on the pool,
+    /// waiting for every merge to finish. This is where the new segments are
+    /// merged with the old ones. If that merges anything, publish the result,
+    /// with the same document table `table`, as another generation. Returns
+    /// the generation that's current afterwards.
+    ///
+    /// This doesn't run on after the caller returns: the next run on the
+    /// index, such as the next batch of changes in `fingertips watch`, would
+    /// publish a generation with its own document table, and the two mustn't
+    /// race.
+    ///
+    /// The new documents are already published, so if this is interrupted,
+    /// nothing is lost; the next run on the index does the same merges.
+    fn compact(&mut self, generation: u64, table: &DocumentTable) -> io::Result<u64> {
+        let manifest = Manifest::load_existing(&self.paths)?;
+        debug_assert_eq!(manifest.generation, generation);
+        self.checkpoint.published = manifest.segments.iter().map(|(_, file)| file.clone()).collect();
+        self.stacks = load_stacks(manifest.segments);
+
+        let mut compacted = false;
+        loop {
+            self.start_merges();
+            let merged = self.collect_finished_merges(true)?;
+            if merged.is_empty() {
+                break;
+            }
+            compacted = true;
+            self.dispose_of(&merged, &[])?;
+        }
+        if !compacted {
+            return Ok(generation);
+        }
+
+        self.cancel.check()?;
+        let segments = self.take_segments();
+        publish(&self.paths, &segments, table)
     }
 
-    pub fn finish(mut self) -> io::Result<()> {
-        let mut tmp = Vec::with_capacity(NSTREAMS);
-        for stack in self.stacks {
-            for file in stack.into_iter().rev() {
-                tmp.push(file);
-                if tmp.len() == NSTREAMS {
-                    merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+    /// Empty the stacks, once all merges are finished, and return the files
+    /// that were in them, oldest first, each with its level.
+    fn take_segments(&mut self) -> Vec<(usize, PathBuf)> {
+        mem::take(&mut self.stacks)
+            .into_iter()
+            .enumerate()
+            .rev()
+            .flat_map(|(level, stack)| stack.into_iter().map(move |segment| (level, segment)))
+            .map(|(level, segment)| match segment {
+                Segment::Ready(file) => (level, file),
+                Segment::Merging { .. } => unreachable!("all merges are finished"),
+            })
+            .collect()
+    }
+
+    /// Dispose of `files`, which have been merged into something else,
+    /// according to the `InputPolicy`. Files that belong to the index being
+    /// added to, and files in `keep`, are left alone either way.
+    fn dispose_of(&self, files: &[PathBuf], keep: &[PathBuf]) -> io::Result<()> {
+        let files: Vec<PathBuf> = files
+            .iter()
+            .filter(|file| !self.checkpoint.published.contains(file) && !keep.contains(file))
+            .cloned()
+            .collect();
+        match self.options.inputs {
+            InputPolicy::Delete => self.tmp_dir.remove(&files),
+            InputPolicy::Keep => {
+                for file in &files {
+                    self.tmp_dir.keep(file);
                 }
+                Ok(())
             }
         }
+    }
 
-        if tmp.len() > 1 {
-            merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+    /// Merge `files` in groups of `fan_in`, in parallel, and return the
+    /// merged files. Inputs are disposed of once they're merged, except
+    /// those in `keep`.
+    fn merge_pass(&self, files: Vec<PathBuf>, level: usize, keep: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let pending: Vec<_> = files
+            .chunks(self.options.fan_in)
+            .map(|group| match group {
+                [_] => (group.to_vec(), None),
+                _ => (group.to_vec(), Some(self.pool.start(group.to_vec(), level))),
+            })
+            .collect();
+        let mut merged = vec![];
+        for (inputs, result) in pending {
+            match result {
+                None => merged.extend(inputs),
+                Some(result) => {
+                    merged.push(receive(&result)?);
+                    self.dispose_of(&inputs, keep)?;
+                }
+            }
         }
-        assert!(tmp.len() <= 1);
-        match tmp.pop() {
-            Some(last_file) => fs::rename(last_file, self.output_dir.join(MERGED_FILENAME)),
-            None => Err(io::Error::other(
-                "no documents were parsed or none contained any words",
-            )),
+        Ok(merged)
+    }
+
+    /// Start a merge for every level that has `fan_in` files ready to merge.
+    fn start_merges(&mut self) {
+        let fan_in = self.options.fan_in;
+        let mut level = 0;
+        while level < self.stacks.len() {
+            let stack = &mut self.stacks[level];
+            let ready = stack.len() >= fan_in
+                && stack[..fan_in].iter().all(|s| matches!(s, Segment::Ready(_)));
+            if !ready {
+                level += 1;
+                continue;
+            }
+
+            let inputs: Vec<PathBuf> = stack
+                .drain(..fan_in)
+                .map(|segment| match segment {
+                    Segment::Ready(file) => file,
+                    Segment::Merging { .. } => unreachable!(),
+                })
+                .collect();
+            let result = self.pool.start(inputs.clone(), level);
+            if level + 1 == self.stacks.len() {
+                self.stacks.push(vec![]);
+            }
+            self.stacks[level + 1].push(Segment::Merging { inputs, result });
         }
     }
+
+    /// Find merges that have finished, and replace them in the stacks with
+    /// their output files. If `wait` is true, wait for all merges to finish.
+    /// Returns the input files of the finished merges, which can be deleted
+    /// once a new checkpoint is saved.
+    fn collect_finished_merges(&mut self, wait: bool) -> io::Result<Vec<PathBuf>> {
+        let mut merged = vec![];
+        for segment in self.stacks.iter_mut().flatten() {
+            if let Segment::Merging { inputs, result } = segment {
+                let file = if wait {
+                    receive(result)?
+                } else {
+                    match result.try_recv() {
+                        Ok(output) => output?,
+                        Err(mpsc::TryRecvError::Empty) => continue,
+                        Err(mpsc::TryRecvError::Disconnected) => return Err(merge_thread_died()),
+                    }
+                };
+                merged.append(inputs);
+                *segment = Segment::Ready(file);
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Save a checkpoint listing every file we still need, oldest first,
+    /// starting with the segments of the index being added to. For merges
+    /// that are still running, that's their input files, which are listed as
+    /// being on the level below.
+    fn save_checkpoint(&mut self) -> io::Result<()> {
+        let mut segments = self.base.clone();
+        for (level, stack) in self.stacks.iter().enumerate().rev() {
+            for segment in stack {
+                match segment {
+                    Segment::Ready(file) => segments.push((level, file.clone())),
+                    Segment::Merging { inputs, .. } => {
+                        segments.extend(inputs.iter().map(|file| (level - 1, file.clone())));
+                    }
+                }
+            }
+        }
+        self.checkpoint.segments = segments;
+        self.checkpoint.save(&self.paths.checkpoint())?;
+        for file in self.checkpoint.files() {
+            self.tmp_dir.keep(file);
+        }
+        Ok(())
+    }
 }
 
-fn merge_streams(files: Vec<PathBuf>, out: BufWriter<File>) -> io::Result<()> {
-    let mut streams: Vec<IndexFileReader> = files
-        .into_iter()
-        .map(IndexFileReader::open_and_delete)
-        .collect::<io::Result<_>>()?;
+impl Drop for FileMerge {
+    fn drop(&mut self) {
+        // If we didn't finish, something went wrong, and we're giving up.
+        // Tell any merges that are still running to stop, rather than waiting
+        // for them to finish.
+        if !self.finished {
+            self.cancel.cancel();
+        }
+    }
+}
 
-    let mut output = IndexFileWriter::new(out)?;
+/// Load `segments`, oldest first, each with its level, into stacks. Files in
+/// the stacks must be in document order, top level first, so no file can be
+/// on a higher level than the one before it.
+fn load_stacks(segments: Vec<(usize, PathBuf)>) -> Vec<Vec<Segment>> {
+    let mut stacks: Vec<Vec<Segment>> = vec![];
+    let mut max_level = usize::MAX;
+    for (level, file) in segments {
+        let level = level.min(max_level);
+        max_level = level;
+        if level >= stacks.len() {
+            stacks.resize_with(level + 1, Vec::new);
+        }
+        stacks[level].push(Segment::Ready(file));
+    }
+    stacks
+}
 
-    let mut point: u64 = 0;
-    let mut count = streams.iter().filter(|s| s.peek().is_some()).count();
-    while count > 0 {
-        let mut term = None;
-        let mut nbytes = 0;
-        let mut df = 0;
-        for s in &streams {
-            match s.peek() {
-                None => {}
-                Some(entry) => {
-                    if term.is_none() || entry.term < *term.as_ref().unwrap() {
-                        term = Some(entry.term.clone()); // XXX LAME clone
-                        nbytes = entry.nbytes;
-                        df = entry.df;
-                    } else if entry.term == *term.as_ref().unwrap() {
-                        nbytes += entry.nbytes;
-                        df += entry.df;
+/// Wait for a background merge to finish and get its output file.
+fn receive(result: &mpsc::Receiver<io::Result<PathBuf>>) -> io::Result<PathBuf> {
+    result.recv().map_err(|_| merge_thread_died())?
+}
+
+fn merge_thread_died() -> io::Error {
+    io::Error::other("merge thread exited unexpectedly")
+}
+
+/// A merge waiting for a thread to run it.
+struct MergeJob {
+    inputs: Vec<PathBuf>,
+    level: usize,
+    result: mpsc::Sender<io::Result<PathBuf>>,
+}
+
+/// The threads that run merges in the background.
+///
+/// Merges are started in the order they're submitted. No merge ever waits on
+/// another, since `FileMerge` only submits merges whose inputs are ready.
+struct MergePool {
+    /// Where to send jobs, or `None` if there are no threads and merges should
+    /// run right away.
+    jobs: Option<mpsc::Sender<MergeJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    deleted: Arc<Tombstones>,
+    tmp_dir: TmpDir,
+    progress: Progress,
+    cancel: Cancel,
+}
+
+impl MergePool {
+    fn new(
+        nthreads: usize,
+        deleted: &Arc<Tombstones>,
+        tmp_dir: &TmpDir,
+        progress: &Progress,
+        cancel: &Cancel,
+    ) -> MergePool {
+        let mut pool = MergePool {
+            jobs: None,
+            workers: vec![],
+            deleted: deleted.clone(),
+            tmp_dir: tmp_dir.clone(),
+            progress: progress.clone(),
+            cancel: cancel.clone(),
+        };
+        if nthreads == 0 {
+            return pool;
+        }
+
+        let (sender, receiver) = mpsc::channel::<MergeJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        pool.jobs = Some(sender);
+        pool.workers = (0..nthreads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let deleted = deleted.clone();
+                let tmp_dir = tmp_dir.clone();
+                let progress = progress.clone();
+                let cancel = cancel.clone();
+                thread::spawn(move || {
+                    loop {
+                        let next = receiver.lock().unwrap().recv();
+                        let Ok(job) = next else { break };
+                        let output = merge(&job.inputs, job.level, &deleted, &tmp_dir, &progress, &cancel);
+                        let _ = job.result.send(output);
                     }
-                }
+                })
+            })
+            .collect();
+        pool
+    }
+
+    /// Start merging `inputs` into a new temporary file. Returns a receiver
+    /// for the name of the file, once it's done.
+    fn start(&self, inputs: Vec<PathBuf>, level: usize) -> mpsc::Receiver<io::Result<PathBuf>> {
+        let (sender, receiver) = mpsc::channel();
+        match &self.jobs {
+            Some(jobs) => {
+                // If the threads are gone, `sender` is dropped, and the
+                // caller finds out when it tries to receive the result.
+                let _ = jobs.send(MergeJob { inputs, level, result: sender });
+            }
+            None => {
+                let output = merge(&inputs, level, &self.deleted, &self.tmp_dir, &self.progress, &self.cancel);
+                let _ = sender.send(output);
             }
         }
-        let term = term.expect("bug in algorithm!");
+        receiver
+    }
+}
 
-        for s in &mut streams {
-            if s.is_at(&term) {
-                s.move_entry_to(&mut output)?;
-                if s.peek().is_none() {
-                    count -= 1;
-                }
+impl Drop for MergePool {
+    fn drop(&mut self) {
+        // Closing the job queue tells the threads to exit once they're done.
+        // Wait for them, so that they're not still writing temporary files
+        // after we've cleaned up.
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Merge `files` into a new temporary file, returning its name. Hits for
+/// documents in `deleted` are left out. The input files are left in place.
+fn merge(
+    files: &[PathBuf],
+    level: usize,
+    deleted: &Tombstones,
+    tmp_dir: &TmpDir,
+    progress: &Progress,
+    cancel: &Cancel,
+) -> io::Result<PathBuf> {
+    let (filename, out) = tmp_dir.create()?;
+    progress.event(Event::MergeStarted { level, inputs: files.len() });
+    let bytes = merge_streams(files, &filename, out, deleted, cancel)?;
+    tmp_dir.add_usage(bytes);
+    progress.event(Event::MergeFinished { level, path: &filename, bytes });
+    Ok(filename)
+}
+
+/// Merge `files` into a single index file, `filename`, written to `out`,
+/// purging hits for documents in `deleted`. Returns the size of the merged
+/// file. Stops with an error if `cancel` is set.
+///
+/// This is a k-way merge. The streams are kept in a heap, ordered by their
+/// next term, so finding the next term to write takes O(log k) time rather
+/// than a scan of every stream.
+fn merge_streams(
+    files: &[PathBuf],
+    filename: &Path,
+    out: BufWriter<File>,
+    deleted: &Tombstones,
+    cancel: &Cancel,
+) -> io::Result<u64> {
+    let mut heap = BinaryHeap::with_capacity(files.len());
+    for (index, file) in files.iter().enumerate() {
+        let reader = IndexFileReader::open(file)?;
+        if reader.peek().is_some() {
+            heap.push(Reverse(Stream { reader, index }));
+        }
+    }
+
+    let mut output = IndexFileWriter::new(filename.to_path_buf(), out)?;
+    let mut group = Vec::with_capacity(files.len());
+    while let Some(Reverse(first)) = heap.pop() {
+        cancel.check()?;
+
+        // Gather every stream whose next term is the same as `first`'s. The
+        // heap hands them over in file order, which is document id order,
+        // so their hits can simply be concatenated.
+        group.push(first);
+        while heap.peek().is_some_and(|Reverse(s)| s.term() == group[0].term()) {
+            let Reverse(stream) = heap.pop().unwrap();
+            group.push(stream);
+        }
+
+        let offset = output.offset();
+        let mut term = None;
+        let mut df = 0;
+        let mut nbytes = 0;
+        for mut stream in group.drain(..) {
+            let entry = stream.reader.move_entry_to(&mut output, deleted)?;
+            df += entry.df;
+            nbytes += entry.nbytes;
+            term.get_or_insert(entry.term);
+            if stream.reader.peek().is_some() {
+                heap.push(Reverse(stream));
             }
         }
-        output.write_contents_entry(term, df, point, nbytes);
-        point += nbytes;
+        // A term whose every hit was deleted is left out altogether.
+        if df > 0 {
+            output.write_contents_entry(term.expect("bug in algorithm!"), df, offset, nbytes);
+        }
     }
 
-    assert!(streams.iter().all(|s| s.peek().is_none()));
     output.finish()
 }
 
-fn merge_reversed(filenames: &mut Vec<PathBuf>, tmp_dir: &mut TmpDir) -> io::Result<()> {
-    filenames.reverse();
-    let (merged_filename, out) = tmp_dir.create()?;
-    let mut to_merge = Vec::with_capacity(NSTREAMS);
-    mem::swap(filenames, &mut to_merge);
-    merge_streams(to_merge, out)?;
-    filenames.push(merged_filename);
-    Ok(())
+/// Join `parts`, the partial indexes of the single document `doc_id`, into
+/// one temporary file, and return its name. The parts are left in place.
+///
+/// A document too big to index in memory is indexed a part at a time (see
+/// `InMemoryIndex::from_reader`). A term can be in several parts, each with a
+/// hit for the same document. That's not allowed in an index file, and simply
+/// merging the parts would keep them all. Instead, the hits for each term are
+/// joined into one: the counts are added up, and the offsets are copied from
+/// each part in turn, oldest first, which keeps them in order. The counts
+/// come from the tables of contents, so the offsets can be streamed straight
+/// from the parts to the output without holding a whole hit in memory.
+pub fn join_parts(
+    doc_id: usize,
+    parts: &[PathBuf],
+    tmp_dir: &TmpDir,
+    progress: &Progress,
+) -> io::Result<PathBuf> {
+    let mut heap = BinaryHeap::with_capacity(parts.len());
+    for (index, file) in parts.iter().enumerate() {
+        let reader = IndexFileReader::open(file)?;
+        if reader.peek().is_some() {
+            heap.push(Reverse(Stream { reader, index }));
+        }
+    }
+
+    let (filename, out) = tmp_dir.create()?;
+    let mut output = IndexFileWriter::new(filename.clone(), out)?;
+    let mut group = Vec::with_capacity(parts.len());
+    while let Some(Reverse(first)) = heap.pop() {
+        group.push(first);
+        while heap.peek().is_some_and(|Reverse(s)| s.term() == group[0].term()) {
+            let Reverse(stream) = heap.pop().unwrap();
+            group.push(stream);
+        }
+
+        // Each part's hit is its header plus 4 bytes per offset.
+        // `move_offsets_to` checks that the entries say so.
+        let count: u64 = group
+            .iter()
+            .map(|s| s.reader.peek().unwrap().nbytes.saturating_sub(HIT_HEADER_SIZE as u64) / 4)
+            .sum();
+        let count = u32::try_from(count)
+            .map_err(|_| io::Error::other("too many words in one document"))?;
+        let offset = output.offset();
+        let mut header = Vec::with_capacity(HIT_HEADER_SIZE);
+        header.write_u32::<LittleEndian>(doc_id as u32)?;
+        header.write_u32::<LittleEndian>(count)?;
+        output.write_main(&header)?;
+
+        let mut term = None;
+        for mut stream in group.drain(..) {
+            let entry = stream.reader.move_offsets_to(&mut output)?;
+            term.get_or_insert(entry.term);
+            if stream.reader.peek().is_some() {
+                heap.push(Reverse(stream));
+            }
+        }
+        let nbytes = output.offset() - offset;
+        output.write_contents_entry(term.expect("bug in algorithm!"), 1, offset, nbytes);
+    }
+
+    let bytes = output.finish()?;
+    tmp_dir.add_usage(bytes);
+    progress.event(Event::TmpFileWritten { path: &filename, bytes });
+    Ok(filename)
+}
+
+/// One of the input files in a merge, ordered by its next term. Ties are
+/// broken by position in the list of files being merged.
+struct Stream {
+    reader: IndexFileReader,
+    index: usize,
+}
+
+impl Stream {
+    fn term(&self) -> &str {
+        &self.reader.peek().expect("exhausted streams aren't kept").term
+    }
+}
+
+impl Ord for Stream {
+    fn cmp(&self, other: &Stream) -> Ordering {
+        self.term().cmp(other.term()).then(self.index.cmp(&other.index))
+    }
+}
+
+impl PartialOrd for Stream {
+    fn partial_cmp(&self, other: &Stream) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Stream {
+    fn eq(&self, other: &Stream) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Stream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::BufReader;
+
+    use crate::index::{hit_doc_id, hit_offsets, Hit, InMemoryIndex};
+    use crate::progress::ProgressMode;
+    use crate::tests::test_dir;
+    use crate::write::write_index_to_tmp_file;
+
+    /// Every term in the index file `path`, with its hits.
+    fn read_all(path: &Path) -> Vec<(String, Vec<Hit>)> {
+        let mut reader = IndexFileReader::open(path).unwrap();
+        let mut entries = vec![];
+        while reader.peek().is_some() {
+            let (entry, hits) = reader.read_entry_hits().unwrap();
+            entries.push((entry.term, hits));
+        }
+        entries
+    }
+
+    #[test]
+    fn join_parts_matches_whole_document() {
+        let dir = test_dir("join-parts");
+        let tmp_dir = TmpDir::new(&dir, "index").unwrap();
+        let progress = Progress::new(ProgressMode::Quiet, 0);
+        let text = "the cat sat on the mat, and the dog sat on the cat. ".repeat(20);
+
+        let mut parts = vec![];
+        let reader = BufReader::with_capacity(16, text.as_bytes());
+        let rest = InMemoryIndex::from_reader(3, reader, 200, |part| {
+            parts.push(write_index_to_tmp_file(part, &tmp_dir, &progress)?);
+            Ok(())
+        })
+        .unwrap();
+        parts.push(write_index_to_tmp_file(rest, &tmp_dir, &progress).unwrap());
+        assert!(parts.len() > 2);
+
+        let joined = read_all(&join_parts(3, &parts, &tmp_dir, &progress).unwrap());
+        let whole = InMemoryIndex::from_single_document(3, text);
+        let mut expected: Vec<_> = whole.map.into_iter().collect();
+        expected.sort();
+        assert_eq!(joined, expected);
+        for (_, hits) in &joined {
+            assert_eq!(hit_doc_id(&hits[0]), 3);
+            assert!(hit_offsets(&hits[0]).is_sorted());
+        }
+        drop(tmp_dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_streams_interleaves_terms_and_purges_deleted() {
+        let dir = test_dir("merge-streams");
+        let tmp_dir = TmpDir::new(&dir, "index").unwrap();
+        let progress = Progress::new(ProgressMode::Quiet, 0);
+        let docs = ["apple banana", "banana cherry", "cherry apple date", "elderberry"];
+        let files: Vec<PathBuf> = docs
+            .iter()
+            .enumerate()
+            .map(|(doc_id, text)| {
+                let index = InMemoryIndex::from_single_document(doc_id, text.to_string());
+                write_index_to_tmp_file(index, &tmp_dir, &progress).unwrap()
+            })
+            .collect();
+
+        let mut deleted = Tombstones::new();
+        deleted.delete(3);
+        let (filename, out) = tmp_dir.create().unwrap();
+        merge_streams(&files, &filename, out, &deleted, &Cancel::new()).unwrap();
+
+        let merged: HashMap<String, Vec<usize>> = read_all(&filename)
+            .into_iter()
+            .map(|(term, hits)| (term, hits.iter().map(|hit| hit_doc_id(hit)).collect()))
+            .collect();
+        let expected: HashMap<String, Vec<usize>> = [
+            ("apple", vec![0, 2]),
+            ("banana", vec![0, 1]),
+            ("cherry", vec![1, 2]),
+            ("date", vec![2]),
+        ]
+        .into_iter()
+        .map(|(term, ids)| (term.to_string(), ids))
+        .collect();
+        // "elderberry" was only in a deleted document, so it's gone.
+        assert_eq!(merged, expected);
+        let terms: Vec<String> = read_all(&filename).into_iter().map(|(term, _)| term).collect();
+        assert!(terms.is_sorted());
+        drop(tmp_dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_streams_copies_and_skips_large_hits() {
+        let dir = test_dir("merge-large-hits");
+        let tmp_dir = TmpDir::new(&dir, "index").unwrap();
+        let progress = Progress::new(ProgressMode::Quiet, 0);
+        // A hit with a million offsets, 4 MB of them.
+        let big = 1_000_000;
+        let docs = ["word ".repeat(big), "word other".to_string()];
+        let files: Vec<PathBuf> = docs
+            .iter()
+            .enumerate()
+            .map(|(doc_id, text)| {
+                let index = InMemoryIndex::from_single_document(doc_id, text.clone());
+                write_index_to_tmp_file(index, &tmp_dir, &progress).unwrap()
+            })
+            .collect();
+
+        // Deleting the small document copies the big hit, offset by offset.
+        let mut deleted = Tombstones::new();
+        deleted.delete(1);
+        let (filename, out) = tmp_dir.create().unwrap();
+        merge_streams(&files, &filename, out, &deleted, &Cancel::new()).unwrap();
+        let merged = read_all(&filename);
+        assert_eq!(merged.len(), 1);
+        let (term, hits) = &merged[0];
+        assert_eq!((term.as_str(), hits.len(), hit_doc_id(&hits[0])), ("word", 1, 0));
+        assert_eq!(hit_offsets(&hits[0]).count(), big);
+        assert!(hit_offsets(&hits[0]).enumerate().all(|(i, offset)| offset as usize == i));
+
+        // Deleting the big document skips over its hit.
+        let mut deleted = Tombstones::new();
+        deleted.delete(0);
+        let (filename, out) = tmp_dir.create().unwrap();
+        merge_streams(&files, &filename, out, &deleted, &Cancel::new()).unwrap();
+        let merged: Vec<(String, Vec<usize>, Vec<u32>)> = read_all(&filename)
+            .into_iter()
+            .map(|(term, hits)| {
+                let ids = hits.iter().map(|hit| hit_doc_id(hit)).collect();
+                let offsets = hits.iter().flat_map(|hit| hit_offsets(hit)).collect();
+                (term, ids, offsets)
+            })
+            .collect();
+        assert_eq!(
+            merged,
+            vec![("other".to_string(), vec![1], vec![1]), ("word".to_string(), vec![1], vec![0])]
+        );
+        drop(tmp_dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
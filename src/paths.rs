@@ -0,0 +1,142 @@
+//! Where an index is saved, and where its temporary files go while it's being
+//! built.
+//!
+//! These can be different places. For example, temporary files could go on a
+//! fast local disk, while the finished index goes on a shared volume.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::tmp;
+
+/// The name of an index, unless told otherwise.
+pub const DEFAULT_INDEX_NAME: &str = "index";
+
+/// The names of the files that make up an index.
+///
+/// An index named `NAME` is saved as `NAME.N.manifest`, the list of its
+/// segments; `NAME.N.docs`, the document table; and `NAME.N.del`, the deleted
+/// documents. `N` is the generation number stored in `NAME.current` (see
+/// `publish`). The segments themselves, which hold the index data, are
+/// `NAME.s1.dat`, `NAME.s2.dat` and so on. While the index is being built,
+/// there's also `NAME.checkpoint`, and any number of temporary files,
+/// `NAME.tmp00000001.dat` and so on, in the temporary directory. `NAME.lock`
+/// is there whenever the index has been changed.
+#[derive(Clone)]
+pub struct IndexPaths {
+    dir: PathBuf,
+    name: String,
+    tmp_dir: PathBuf,
+}
+
+impl IndexPaths {
+    pub fn new(dir: PathBuf, name: String, tmp_dir: PathBuf) -> IndexPaths {
+        IndexPaths { dir, name, tmp_dir }
+    }
+
+    /// The directory where the index is saved.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The name of the index, which all its files' names start with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The directory for temporary files.
+    pub fn tmp_dir(&self) -> &Path {
+        &self.tmp_dir
+    }
+
+    /// The file that says which generation of the index is current.
+    pub fn current(&self) -> PathBuf {
+        self.file("current")
+    }
+
+    /// The file listing the segments that make up the given generation.
+    pub fn manifest(&self, generation: u64) -> PathBuf {
+        self.file(&format!("{generation}.manifest"))
+    }
+
+    /// The segment file with the given number.
+    pub fn segment(&self, number: u64) -> PathBuf {
+        self.file(&format!("s{number}.dat"))
+    }
+
+    /// The file containing the document table, for the given generation.
+    pub fn docs(&self, generation: u64) -> PathBuf {
+        self.file(&format!("{generation}.docs"))
+    }
+
+    /// The file listing deleted documents, for the given generation.
+    pub fn deleted(&self, generation: u64) -> PathBuf {
+        self.file(&format!("{generation}.del"))
+    }
+
+    /// The file where progress is saved while the index is being built.
+    pub fn checkpoint(&self) -> PathBuf {
+        self.file("checkpoint")
+    }
+
+    /// The file that programs changing the index lock (see `lock`).
+    pub fn lock(&self) -> PathBuf {
+        self.file("lock")
+    }
+
+    /// True if `path` is one of the index's own files, or one of its
+    /// temporary files. These must never be indexed as documents, even when
+    /// the index is kept in the same directory as the documents.
+    pub fn owns(&self, path: &Path) -> bool {
+        let dir = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        path.file_name().is_some_and(|filename| self.is_own_file_name(filename))
+            && self.is_index_dir(dir)
+    }
+
+    /// True if `dir` is the directory where the index or its temporary files
+    /// are kept, however it's spelled.
+    pub fn is_index_dir(&self, dir: &Path) -> bool {
+        let Ok(dir) = fs::canonicalize(dir) else {
+            return false;
+        };
+        [&self.dir, &self.tmp_dir]
+            .iter()
+            .any(|own| fs::canonicalize(own).is_ok_and(|own| own == dir))
+    }
+
+    /// True if `filename`, a name in one of the index's directories, is the
+    /// name of one of the index's files: one of the names above, or the
+    /// `-new` and `.partial` files they're written as before being renamed
+    /// into place. Anything else that happens to start with the index's name,
+    /// like `index.html`, is somebody else's.
+    pub fn is_own_file_name(&self, filename: &OsStr) -> bool {
+        let Some(filename) = filename.to_str() else {
+            return false;
+        };
+        if tmp::tmp_file_number(filename, &self.name).is_some() {
+            return true;
+        }
+        let Some(rest) = filename.strip_prefix(self.name.as_str()).and_then(|rest| rest.strip_prefix('.')) else {
+            return false;
+        };
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if let Some(number) = rest.strip_prefix('s').and_then(|rest| rest.strip_suffix(".dat")) {
+            return is_number(number);
+        }
+        if let Some(number) = rest.strip_prefix('s').and_then(|rest| rest.strip_suffix(".dat.partial")) {
+            return is_number(number);
+        }
+        match rest.split_once('.') {
+            Some((generation, "manifest" | "docs" | "del")) => is_number(generation),
+            _ => matches!(rest, "current" | "current-new" | "lock" | "checkpoint" | "checkpoint-new"),
+        }
+    }
+
+    fn file(&self, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{extension}", self.name))
+    }
+}
@@ -0,0 +1,313 @@
+//! Reporting progress while building an index.
+//!
+//! Indexing a big corpus can take hours, so it's nice to know how it's going.
+//! Each stage of the work reports `Event`s to a shared `Progress` object, which
+//! shows them to the user in one of three ways: not at all, as a progress bar
+//! on stderr, or as a stream of JSON objects on stdout, one per line, for
+//! other programs to consume.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+
+/// How to report progress.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Don't report anything.
+    Quiet,
+
+    /// Draw a progress bar on stderr.
+    Bar,
+
+    /// Write each event to stdout as a line of JSON.
+    Json,
+}
+
+impl ProgressMode {
+    /// Parse the argument of the `--progress` command-line option.
+    pub fn parse(s: &str) -> Result<ProgressMode, Error> {
+        match s {
+            "none" | "quiet" => Ok(ProgressMode::Quiet),
+            "bar" => Ok(ProgressMode::Bar),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(Error::Usage(format!(
+                "unknown progress mode {s:?} (expected none, bar, or json)"
+            ))),
+        }
+    }
+}
+
+/// Something that happened while building an index.
+pub enum Event<'a> {
+    /// We finished reading one of the files named on the command line.
+    FileRead { path: &'a Path, bytes: u64 },
+
+    /// A document was indexed in memory.
+    DocumentIndexed { doc_id: usize, bytes: u64, words: usize },
+
+    /// An in-memory index was saved to a temporary file.
+    TmpFileWritten { path: &'a Path, bytes: u64 },
+
+    /// We started merging `inputs` temporary files into one. `level` is how
+    /// many merges deep we are: level 0 merges files written directly from
+    /// memory, level 1 merges the output of level 0 merges, and so on.
+    MergeStarted { level: usize, inputs: usize },
+
+    /// A merge finished, producing the file `path`.
+    MergeFinished { level: usize, path: &'a Path, bytes: u64 },
+
+    /// The index is complete. `peak_tmp_bytes` is the most disk space that
+    /// temporary files took up at any one time.
+    Finished { path: &'a Path, documents: usize, peak_tmp_bytes: u64 },
+}
+
+/// Running totals, for the progress bar.
+#[derive(Default)]
+struct Totals {
+    input_bytes_read: u64,
+    documents: usize,
+    bytes_indexed: u64,
+    tmp_files: usize,
+    merges: usize,
+    max_merge_level: usize,
+    peak_tmp_bytes: u64,
+}
+
+struct State {
+    mode: ProgressMode,
+    start: Instant,
+    last_draw: Option<Instant>,
+    total_input_bytes: u64,
+    totals: Totals,
+}
+
+/// A handle for reporting progress. Cloning it is cheap, and all clones report
+/// to the same place, so each thread of the pipeline can have its own.
+#[derive(Clone)]
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+}
+
+/// How often to redraw the progress bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the bar part of the progress bar, in characters.
+const BAR_WIDTH: usize = 30;
+
+impl Progress {
+    /// Create a new `Progress` reporting in the given `mode`.
+    ///
+    /// `total_input_bytes` is the total size of all the input files, used to
+    /// estimate how much longer the job will take.
+    pub fn new(mode: ProgressMode, total_input_bytes: u64) -> Progress {
+        Progress {
+            state: Arc::new(Mutex::new(State {
+                mode,
+                start: Instant::now(),
+                last_draw: None,
+                total_input_bytes,
+                totals: Totals::default(),
+            })),
+        }
+    }
+
+    /// Report an event.
+    pub fn event(&self, event: Event) {
+        let mut state = self.state.lock().unwrap();
+        state.totals.add(&event);
+        match state.mode {
+            ProgressMode::Quiet => {}
+            ProgressMode::Json => {
+                let line = event.to_json(state.start.elapsed());
+                let _ = writeln!(io::stdout().lock(), "{line}");
+            }
+            ProgressMode::Bar => {
+                let finished = matches!(event, Event::Finished { .. });
+                let due = state.last_draw.is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL);
+                if due || finished {
+                    state.draw_bar(finished);
+                }
+            }
+        }
+    }
+
+    /// Stop drawing the progress bar, if there is one, so that other messages
+    /// (like errors) can be printed on their own line.
+    pub fn clear(&self) {
+        let state = self.state.lock().unwrap();
+        if state.mode == ProgressMode::Bar && state.last_draw.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl Totals {
+    fn add(&mut self, event: &Event) {
+        match *event {
+            Event::FileRead { bytes, .. } => self.input_bytes_read += bytes,
+            Event::DocumentIndexed { bytes, .. } => {
+                self.documents += 1;
+                self.bytes_indexed += bytes;
+            }
+            Event::TmpFileWritten { .. } => self.tmp_files += 1,
+            Event::MergeStarted { level, .. } => {
+                self.merges += 1;
+                self.max_merge_level = self.max_merge_level.max(level);
+            }
+            Event::Finished { peak_tmp_bytes, .. } => self.peak_tmp_bytes = peak_tmp_bytes,
+            Event::MergeFinished { .. } => {}
+        }
+    }
+}
+
+impl State {
+    fn draw_bar(&mut self, finished: bool) {
+        let elapsed = self.start.elapsed();
+        let fraction = if finished {
+            1.0
+        } else if self.total_input_bytes == 0 {
+            0.0
+        } else {
+            self.totals.input_bytes_read as f64 / self.total_input_bytes as f64
+        };
+        let filled = ((fraction * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+
+        let mut line = format!(
+            "\r[{}{}] {:3.0}% {} docs, {}, {} tmp files",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            self.totals.documents,
+            format_bytes(self.totals.bytes_indexed),
+            self.totals.tmp_files,
+        );
+        if self.totals.merges > 0 {
+            let _ = write!(line, ", merge level {}", self.totals.max_merge_level);
+        }
+        if finished {
+            let _ = write!(
+                line,
+                ", done in {}, peak temp space {}",
+                format_duration(elapsed),
+                format_bytes(self.totals.peak_tmp_bytes),
+            );
+        } else if fraction > 0.0 {
+            // Assume the rest of the input will go as fast as it has so far.
+            let remaining = elapsed.as_secs_f64() * (1.0 - fraction) / fraction;
+            let _ = write!(line, ", ETA {}", format_duration(Duration::from_secs_f64(remaining)));
+        }
+
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "{line}\x1b[K");
+        if finished {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+impl Event<'_> {
+    /// Format this event as a single-line JSON object.
+    fn to_json(&self, elapsed: Duration) -> String {
+        let mut json = String::from("{");
+        let mut field = |name: &str, value: String| {
+            if json.len() > 1 {
+                json.push(',');
+            }
+            let _ = write!(json, "{}:{value}", json_string(name));
+        };
+        match *self {
+            Event::FileRead { path, bytes } => {
+                field("event", json_string("file_read"));
+                field("path", json_string(&path.display().to_string()));
+                field("bytes", bytes.to_string());
+            }
+            Event::DocumentIndexed { doc_id, bytes, words } => {
+                field("event", json_string("document_indexed"));
+                field("doc_id", doc_id.to_string());
+                field("bytes", bytes.to_string());
+                field("words", words.to_string());
+            }
+            Event::TmpFileWritten { path, bytes } => {
+                field("event", json_string("tmp_file_written"));
+                field("path", json_string(&path.display().to_string()));
+                field("bytes", bytes.to_string());
+            }
+            Event::MergeStarted { level, inputs } => {
+                field("event", json_string("merge_started"));
+                field("level", level.to_string());
+                field("inputs", inputs.to_string());
+            }
+            Event::MergeFinished { level, path, bytes } => {
+                field("event", json_string("merge_finished"));
+                field("level", level.to_string());
+                field("path", json_string(&path.display().to_string()));
+                field("bytes", bytes.to_string());
+            }
+            Event::Finished { path, documents, peak_tmp_bytes } => {
+                field("event", json_string("finished"));
+                field("path", json_string(&path.display().to_string()));
+                field("documents", documents.to_string());
+                field("peak_tmp_bytes", peak_tmp_bytes.to_string());
+            }
+        }
+        field("elapsed", format!("{:.3}", elapsed.as_secs_f64()));
+        json.push('}');
+        json
+    }
+}
+
+/// Quote a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format a byte count for humans, like `12.3 MB`.
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: &[&str] = &["bytes", "KB", "MB", "GB", "TB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n} bytes")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Format a duration for humans, like `1h02m03s`.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h{m:02}m{s:02}s")
+    } else if m > 0 {
+        format!("{m}m{s:02}s")
+    } else {
+        format!("{s}s")
+    }
+}
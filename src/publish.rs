@@ -0,0 +1,272 @@
+//! Publishing a finished index.
+//!
+//! An index is made of *segments*, index data files that are never changed
+//! once they're written, plus a document table and tombstones. Which segments
+//! make up the index, and in what order, is listed in a *manifest*. All of
+//! these have to match. Replacing them one at a time, in place, would let a
+//! program reading the index see a new segment with the old document table,
+//! or a file that's only half there. So instead, each time an index is saved,
+//! it gets a new *generation* number, and its files are named after it:
+//! `NAME.1.manifest`, `NAME.1.docs` and `NAME.1.del`, then `NAME.2.manifest`
+//! and so on. A small file, `NAME.current`, says which generation is the
+//! current one:
+//!
+//! ```text
+//! fingertips index 3
+//! generation 2
+//! ```
+//!
+//! The manifest lists the segments in document order, oldest first, each with
+//! its level in the stacks of files that `FileMerge` keeps (see `merge`):
+//!
+//! ```text
+//! fingertips manifest 1
+//! next-segment 8
+//! segment 2 index.s3.dat
+//! segment 0 index.s7.dat
+//! ```
+//!
+//! Segments are shared between generations. Adding documents to an index only
+//! writes new segments for them; the merges of old segments that the merge
+//! policy calls for are published afterwards, as a generation of their own.
+//! Deleting documents doesn't write any segments.
+//!
+//! The new generation's files are written and synced to disk first, and only
+//! then is `NAME.current` atomically replaced to point at them. A reader that
+//! reads `NAME.current` and then opens the files it names always gets a
+//! complete index, either the previous one or the new one, even if the
+//! machine crashes partway through.
+//!
+//! The previous generation is left in place, for the sake of readers that
+//! looked at `NAME.current` just before it changed. The one before that is
+//! deleted, along with any of its segments that are no longer used.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::docs::DocumentTable;
+use crate::error::{Context, Error};
+use crate::paths::IndexPaths;
+use crate::tmp::move_file;
+
+/// The first line of every `NAME.current` file.
+const MAGIC: &str = "fingertips index 3";
+
+/// The first line of every manifest.
+const MANIFEST_MAGIC: &str = "fingertips manifest 1";
+
+/// The list of segments that make up one generation of an index.
+pub struct Manifest {
+    pub generation: u64,
+
+    /// The number to give the next new segment. Segment numbers are never
+    /// reused, so a new segment can't clobber one that a reader of an older
+    /// generation is still using.
+    next_segment: u64,
+
+    /// The segment files, oldest first, each with its level.
+    pub segments: Vec<(usize, PathBuf)>,
+}
+
+impl Manifest {
+    /// Load the manifest of the current generation of the index, if there is
+    /// an index.
+    pub fn load(paths: &IndexPaths) -> io::Result<Option<Manifest>> {
+        match current_generation(paths)? {
+            Some(generation) => Manifest::read(paths, generation).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Load the manifest of the current generation of the index, or fail with
+    /// `Error::NoIndex` if there's no index.
+    pub fn load_existing(paths: &IndexPaths) -> io::Result<Manifest> {
+        Manifest::load(paths)?.ok_or_else(|| Error::NoIndex(paths.dir().to_path_buf()).into())
+    }
+
+    /// Load the manifest of the given generation of the index.
+    fn read(paths: &IndexPaths, generation: u64) -> io::Result<Manifest> {
+        let filename = paths.manifest(generation);
+        let bad = |what: &str| -> io::Error {
+            Error::Corrupt {
+                path: filename.clone(),
+                position: None,
+                what: format!("bad manifest: {what}"),
+            }
+            .into()
+        };
+
+        let mut lines = BufReader::new(File::open(&filename).in_file(&filename)?).lines();
+        if lines.next().transpose().in_file(&filename)?.as_deref() != Some(MANIFEST_MAGIC) {
+            return Err(bad("unrecognized format"));
+        }
+        let mut manifest = Manifest { generation, next_segment: 1, segments: vec![] };
+        for line in lines {
+            let line = line.in_file(&filename)?;
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad(&line))?;
+            match key {
+                "next-segment" => manifest.next_segment = value.parse().map_err(|_| bad(&line))?,
+                "segment" => {
+                    let (level, name) = value.split_once(' ').ok_or_else(|| bad(&line))?;
+                    let level = level.parse().map_err(|_| bad(&line))?;
+                    manifest.segments.push((level, paths.dir().join(name)));
+                }
+                _ => return Err(bad(&line)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Save this manifest, and sync it to disk.
+    fn write(&self, paths: &IndexPaths) -> io::Result<()> {
+        let filename = paths.manifest(self.generation);
+        self.write_to(&filename).in_file(&filename)
+    }
+
+    fn write_to(&self, filename: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        writeln!(out, "{MANIFEST_MAGIC}")?;
+        writeln!(out, "next-segment {}", self.next_segment)?;
+        for (level, path) in &self.segments {
+            let name = path.file_name().expect("segments are files");
+            writeln!(out, "segment {level} {}", Path::new(name).display())?;
+        }
+        out.into_inner()?.sync_all()
+    }
+
+    /// True if `file` is one of this generation's segments.
+    pub fn contains(&self, file: &Path) -> bool {
+        self.segments.iter().any(|(_, segment)| segment == file)
+    }
+
+    /// Load this generation's document table.
+    pub fn read_table(&self, paths: &IndexPaths) -> io::Result<DocumentTable> {
+        DocumentTable::read(&paths.docs(self.generation), &paths.deleted(self.generation))
+    }
+}
+
+/// Find out which generation of the index is current, if any.
+fn current_generation(paths: &IndexPaths) -> io::Result<Option<u64>> {
+    let filename = paths.current();
+    let file = match File::open(&filename) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).in_file(&filename),
+    };
+    let bad = |what: &str| -> io::Error {
+        Error::Corrupt {
+            path: filename.clone(),
+            position: None,
+            what: format!("bad index pointer file: {what}"),
+        }
+        .into()
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    if lines.next().transpose().in_file(&filename)?.as_deref() != Some(MAGIC) {
+        return Err(bad("unrecognized format"));
+    }
+    for line in lines {
+        let line = line.in_file(&filename)?;
+        if let Some(generation) = line.strip_prefix("generation ") {
+            return generation.parse().map(Some).map_err(|_| bad(&line));
+        }
+    }
+    Err(bad("no generation number"))
+}
+
+/// Publish a new generation of the index, made of `segments`, oldest first,
+/// each with its level, and the document table `table`. Returns the new
+/// generation number.
+///
+/// Segments that belong to the current generation are shared with it. Any
+/// others are temporary files, which are moved into place as new segments.
+pub fn publish(
+    paths: &IndexPaths,
+    segments: &[(usize, PathBuf)],
+    table: &DocumentTable,
+) -> io::Result<u64> {
+    let previous = Manifest::load(paths)?;
+    let generation = previous.as_ref().map_or(1, |m| m.generation + 1);
+    let mut manifest = Manifest {
+        generation,
+        next_segment: previous.as_ref().map_or(1, |m| m.next_segment),
+        segments: Vec::with_capacity(segments.len()),
+    };
+
+    // Write the new generation's files. If we crash during this part, the
+    // files are simply never published, and the next run overwrites them.
+    for (level, file) in segments {
+        if previous.as_ref().is_some_and(|m| m.contains(file)) {
+            manifest.segments.push((*level, file.clone()));
+        } else {
+            File::open(file).and_then(|f| f.sync_all()).in_file(file)?;
+            let segment = paths.segment(manifest.next_segment);
+            manifest.next_segment += 1;
+            move_file(file, &segment)?;
+            manifest.segments.push((*level, segment));
+        }
+    }
+    table.write(&paths.docs(generation), &paths.deleted(generation))?;
+    manifest.write(paths)?;
+    sync_dir(paths.dir())?;
+
+    // Switch readers over to them.
+    let current = paths.current();
+    let new_current = current.with_extension("current-new");
+    File::create(&new_current)
+        .and_then(|mut out| {
+            writeln!(out, "{MAGIC}")?;
+            writeln!(out, "generation {generation}")?;
+            out.sync_all()
+        })
+        .in_file(&new_current)?;
+    fs::rename(&new_current, &current).in_file(&current)?;
+    sync_dir(paths.dir())?;
+
+    // Clean up the generation before last, and any segments that only it
+    // was using.
+    if let Some(previous) = previous
+        && let Some(old) = previous.generation.checked_sub(1)
+    {
+        match Manifest::read(paths, old) {
+            Ok(old_manifest) => {
+                for (_, segment) in &old_manifest.segments {
+                    if !previous.contains(segment) && !manifest.contains(segment) {
+                        remove_if_exists(segment)?;
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        for file in [paths.manifest(old), paths.docs(old), paths.deleted(old)] {
+            remove_if_exists(&file)?;
+        }
+    }
+    Ok(generation)
+}
+
+/// Delete a file, if it exists.
+fn remove_if_exists(file: &Path) -> io::Result<()> {
+    match fs::remove_file(file) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err).in_file(file),
+        _ => Ok(()),
+    }
+}
+
+/// Make sure the entries in `dir` (such as newly created or renamed files)
+/// have reached the disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir).and_then(|dir| dir.sync_all()).in_file(dir)
+}
+
+/// Elsewhere, directories can't be opened as files to sync them (on Windows,
+/// `File::open` fails on a directory); renames there are as durable as the
+/// filesystem makes them.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
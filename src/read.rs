@@ -1,21 +1,26 @@
 //! Reading index files linearly from disk, a capability needed for merging
 //! index files.
 
-use std::fs::{self, File};
+use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, BufReader, SeekFrom};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 
-use crate::write::IndexFileWriter;
+use crate::error::{Context, Error, Position, Section};
+use crate::index::{hit_doc_id, Hit, HIT_HEADER_SIZE};
+use crate::tombstones::Tombstones;
+use crate::write::{IndexFileWriter, CONTENTS_OFFSET_POSITION, FORMAT_VERSION, HEADER_SIZE, MAGIC};
 
 /// A `IndexFileReader` does a single linear pass over an index file from
 /// beginning to end. Needless to say, this is not how an index is normally
-/// used! This is used only when merging multiple index files.
+/// used! This is used when merging multiple index files, and by `fingertips
+/// dump`.
 ///
-/// The only way to advance through the file is to use the `.move_entry_to()`
-/// method.
+/// The ways to advance through the file are `.move_entry_to()`, which copies
+/// an entry to another index file, `.read_entry_hits()`, and `.skip_entry()`.
+/// None of them change the file.
 pub struct IndexFileReader {
     /// Reader that reads the actual index data.
     ///
@@ -26,15 +31,28 @@ pub struct IndexFileReader {
 
     /// Reader that reads the table of contents. (Since this table is stored at
     /// the end of the file, we have to begin by `seek`ing to it; see the code
-    /// in `IndexFileReader::open_and_delete`.)
+    /// in `IndexFileReader::open`.)
     contents: BufReader<File>,
 
+    /// The file being read, for error messages.
+    filename: PathBuf,
+
+    /// What the file's header says.
+    header: Header,
+
+    /// Where in the file the table of contents entry after `next` starts.
+    contents_position: u64,
+
     /// The next entry in the table of contents, if any; or `None` if we've
     /// reached the end of the table. `IndexFileReader` always reads ahead one
     /// entry in the contents and stores it here.
     next: Option<Entry>,
 }
 
+/// Size of the fixed part of a table of contents entry: `offset`, `nbytes`,
+/// `df`, and the length of the term.
+const CONTENTS_ENTRY_HEADER_SIZE: u64 = 8 + 8 + 4 + 4;
+
 /// An entry in the table of contents of an index file.
 ///
 /// Each entry in the table of contents is small. It consists of a string, the
@@ -50,7 +68,6 @@ pub struct Entry {
     pub df: u32,
 
     /// Offset of the index data for this term from the beginning of the file, in bytes.
-    #[expect(dead_code)]
     pub offset: u64,
 
     /// Length of the index data for this term, in bytes.
@@ -60,46 +77,55 @@ pub struct Entry {
 impl IndexFileReader {
     /// Open an index file to read it from beginning to end.
     ///
-    /// This deletes the file, which may not work properly on Windows. Patches
-    /// welcome!  On Unix, it works like this: the file immediately disappears
-    /// from its directory, but it'll still take up space on disk until the
-    /// file is closed, which normally happens when the `IndexFileReader` is
-    /// dropped.
-    pub fn open_and_delete<P: AsRef<Path>>(filename: P) -> io::Result<IndexFileReader> {
+    /// The file is left alone, so index files can be read, copied, or merged
+    /// again as often as you like. When merging, what becomes of the inputs
+    /// afterwards is up to the merge (see `merge::InputPolicy`).
+    pub fn open<P: AsRef<Path>>(filename: P) -> io::Result<IndexFileReader> {
         let filename = filename.as_ref();
-        let mut main_raw = File::open(filename)?;
+        let mut main_raw = File::open(filename).in_file(filename)?;
 
         // Read the file header.
-        let contents_offset = main_raw.read_u64::<LittleEndian>()?;
-        println!(
-            "opened {}, table of contents starts at {contents_offset}",
-            filename.display()
-        );
+        let header = read_header(&mut main_raw, filename)?;
+        let contents_offset = header.contents_offset;
 
         // Open again so we have two read heads;
         // move the contents read head to its starting position.
         // Set up buffering.
-        let mut contents_raw = File::open(filename)?;
-        contents_raw.seek(SeekFrom::Start(contents_offset))?;
+        let mut contents_raw = File::open(filename).in_file(filename)?;
+        contents_raw
+            .seek(SeekFrom::Start(contents_offset))
+            .in_index_file(filename, Section::Contents, contents_offset)?;
         let main = BufReader::new(main_raw);
         let mut contents = BufReader::new(contents_raw);
 
         // We always read ahead one entry, so load the first entry right away.
-        let first = IndexFileReader::read_entry(&mut contents)?;
-
-        fs::remove_file(filename)?; // YOLO
+        let mut contents_position = contents_offset;
+        let first =
+            IndexFileReader::read_entry(&mut contents, filename, &mut contents_position, header.len)?;
 
         Ok(IndexFileReader {
             main,
             contents,
+            filename: filename.to_path_buf(),
+            header,
+            contents_position,
             next: first,
         })
     }
 
-    /// Read the next entry from the table of contents.
+    /// Read the next entry from the table of contents of the index file
+    /// `filename`, which is `len` bytes long. `position` is where the entry
+    /// starts; it's advanced past it.
     ///
     /// Returns `Ok(None)` if we have reached the end of the file.
-    fn read_entry(f: &mut BufReader<File>) -> io::Result<Option<Entry>> {
+    fn read_entry(
+        f: &mut BufReader<File>,
+        filename: &Path,
+        position: &mut u64,
+        len: u64,
+    ) -> io::Result<Option<Entry>> {
+        let start = *position;
+
         // If the first read here fails with `UnexpectedEof`,
         // that's considered a success, with no entry read.
         let offset = match f.read_u64::<LittleEndian>() {
@@ -108,20 +134,34 @@ impl IndexFileReader {
                 if err.kind() == io::ErrorKind::UnexpectedEof {
                     return Ok(None);
                 } else {
-                    return Err(err);
+                    return Err(err).in_index_file(filename, Section::Contents, start);
                 }
             }
         };
 
-        let nbytes = f.read_u64::<LittleEndian>()?;
-        let df = f.read_u32::<LittleEndian>()?;
-        let term_len = f.read_u32::<LittleEndian>()? as usize;
-        let mut bytes = vec![0; term_len];
-        f.read_exact(&mut bytes)?;
-        let term = match String::from_utf8(bytes) {
-            Ok(s) => s,
-            Err(_) => return Err(io::Error::other("unicode fail")),
+        // The term's length comes from the file, so check it against what's
+        // left of the file before making room for it.
+        let read_rest = |f: &mut BufReader<File>| -> io::Result<(u64, u32, Vec<u8>)> {
+            let nbytes = f.read_u64::<LittleEndian>()?;
+            let df = f.read_u32::<LittleEndian>()?;
+            let term_len = f.read_u32::<LittleEndian>()? as u64;
+            if term_len > len.saturating_sub(start + CONTENTS_ENTRY_HEADER_SIZE) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("term length {term_len} runs past the end of the file"),
+                ));
+            }
+            let mut bytes = vec![0; term_len as usize];
+            f.read_exact(&mut bytes)?;
+            Ok((nbytes, df, bytes))
         };
+        let (nbytes, df, bytes) = read_rest(f).in_index_file(filename, Section::Contents, start)?;
+        *position += CONTENTS_ENTRY_HEADER_SIZE + bytes.len() as u64;
+        let term = String::from_utf8(bytes).map_err(|_| Error::Corrupt {
+            path: filename.to_path_buf(),
+            position: Some(Position::in_index(Section::Contents, start)),
+            what: "term isn't valid UTF-8".to_string(),
+        })?;
 
         Ok(Some(Entry {
             term,
@@ -139,33 +179,290 @@ impl IndexFileReader {
         self.next.as_ref()
     }
 
-    /// True if the next entry is for the given term.
-    pub fn is_at(&self, term: &str) -> bool {
-        match self.next {
-            Some(ref e) => e.term == term,
-            None => false,
+    /// Copy the current entry's index data to the specified output stream,
+    /// leaving out hits for documents in `deleted`, then read the header for
+    /// the next entry. Returns the table of contents entry for the data that
+    /// was copied. (If every hit was left out, its `df` is 0.)
+    ///
+    /// The data is streamed through a small buffer, so this works even for
+    /// entries much too big to fit in memory.
+    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter, deleted: &Tombstones) -> io::Result<Entry> {
+        let mut e = self.next.take().expect("no entry to move");
+        if deleted.is_empty() {
+            out.copy_main_from(&mut self.main, e.nbytes)
+                .in_index_file(&self.filename, Section::Postings, e.offset)?;
+        } else {
+            // Go through the hits one at a time, keeping the live ones. Only
+            // each hit's header is read; the offsets, which there can be
+            // millions of in a large document, are copied or skipped.
+            let end = self.data_end(&e)?;
+            let mut remaining = e.nbytes;
+            e.df = 0;
+            e.nbytes = 0;
+            while remaining > 0 {
+                let position = end - remaining;
+                let (header, len) = read_hit_header(&mut self.main, remaining)
+                    .in_index_file(&self.filename, Section::Postings, position)?;
+                let offsets_len = len - HIT_HEADER_SIZE as u64;
+                let offsets_position = position + HIT_HEADER_SIZE as u64;
+                if deleted.is_deleted(hit_doc_id(&header)) {
+                    self.main
+                        .seek_relative(offsets_len as i64)
+                        .in_index_file(&self.filename, Section::Postings, offsets_position)?;
+                } else {
+                    out.write_main(&header)?;
+                    out.copy_main_from(&mut self.main, offsets_len)
+                        .in_index_file(&self.filename, Section::Postings, offsets_position)?;
+                    e.df += 1;
+                    e.nbytes += len;
+                }
+                remaining -= len;
+            }
         }
+        self.read_next_entry()?;
+        Ok(e)
     }
 
-    /// Copy the current entry to the specified output stream, then read the
-    /// header for the next entry.
-    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<()> {
-        // This block limits the scope of borrowing `self.next` (for `e`),
-        // because after this block is over we'll want to assign to `self.next`.
-        {
-            let e = self.next.as_ref().expect("no entry to move");
-            if e.nbytes > usize::MAX as u64 {
-                // This can only happen on 32-bit platforms.
-                return Err(io::Error::other(
-                    "computer not big enough to hold index entry",
-                ));
+    /// Read the current entry's hits, then read the header for the next
+    /// entry. Returns the table of contents entry and the hits.
+    pub fn read_entry_hits(&mut self) -> io::Result<(Entry, Vec<Hit>)> {
+        let e = self.next.take().expect("no entry to read");
+        let end = self.data_end(&e)?;
+        let mut position = e.offset;
+        // Every hit takes at least `HIT_HEADER_SIZE` bytes, which limits how
+        // many there can be, whatever `df` says.
+        let mut hits = Vec::with_capacity((e.df as u64).min(e.nbytes / HIT_HEADER_SIZE as u64) as usize);
+        while position < end {
+            let hit = read_hit(&mut self.main, end - position)
+                .in_index_file(&self.filename, Section::Postings, position)?;
+            position += hit.len() as u64;
+            hits.push(hit);
+        }
+        self.read_next_entry()?;
+        Ok((e, hits))
+    }
+
+    /// Copy the offsets in the current entry's hit to `out`, leaving off the
+    /// hit's document id and count, then read the header for the next entry.
+    /// Returns the table of contents entry.
+    ///
+    /// This is for joining the parts of a document that was indexed a piece
+    /// at a time (see `merge::join_parts`), so the entry must have exactly one
+    /// hit. Like `move_entry_to`, this streams the data.
+    pub fn move_offsets_to(&mut self, out: &mut IndexFileWriter) -> io::Result<Entry> {
+        let e = self.next.take().expect("no entry to move");
+        self.data_end(&e)?;
+        let mut header = [0; HIT_HEADER_SIZE];
+        self.main
+            .read_exact(&mut header)
+            .in_index_file(&self.filename, Section::Postings, e.offset)?;
+        let count = LittleEndian::read_u32(&header[4..8]) as u64;
+        if e.df != 1 || HIT_HEADER_SIZE as u64 + 4 * count != e.nbytes {
+            return Err(Error::Corrupt {
+                path: self.filename.clone(),
+                position: Some(Position::in_index(Section::Postings, e.offset)),
+                what: format!("expected a single hit for {:?}", e.term),
             }
-            let mut buf = vec![0; e.nbytes as usize];
-            self.main.read_exact(&mut buf)?;
-            out.write_main(&buf)?;
+            .into());
         }
+        let position = e.offset + HIT_HEADER_SIZE as u64;
+        out.copy_main_from(&mut self.main, 4 * count)
+            .in_index_file(&self.filename, Section::Postings, position)?;
+        self.read_next_entry()?;
+        Ok(e)
+    }
+
+    /// Skip over the current entry's index data without reading it, then read
+    /// the header for the next entry. Returns the table of contents entry.
+    pub fn skip_entry(&mut self) -> io::Result<Entry> {
+        let e = self.next.take().expect("no entry to skip");
+        self.main
+            .seek_relative(e.nbytes as i64)
+            .in_index_file(&self.filename, Section::Postings, e.offset)?;
+        self.read_next_entry()?;
+        Ok(e)
+    }
 
-        self.next = Self::read_entry(&mut self.contents)?;
+    /// The file's header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read ahead the next entry in the table of contents.
+    fn read_next_entry(&mut self) -> io::Result<()> {
+        self.next = Self::read_entry(
+            &mut self.contents,
+            &self.filename,
+            &mut self.contents_position,
+            self.header.len,
+        )?;
         Ok(())
     }
+
+    /// Where the data for the entry `e` ends. It's damage if that's past the
+    /// start of the table of contents.
+    fn data_end(&self, e: &Entry) -> io::Result<u64> {
+        match e.offset.checked_add(e.nbytes) {
+            Some(end) if end <= self.header.contents_offset => Ok(end),
+            _ => Err(Error::Corrupt {
+                path: self.filename.clone(),
+                position: Some(Position::in_index(Section::Postings, e.offset)),
+                what: format!("the data for {:?} runs into the table of contents", e.term),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Read the whole table of contents of the index file `filename`.
+pub fn read_contents(filename: &Path) -> io::Result<Vec<Entry>> {
+    let mut file = File::open(filename).in_file(filename)?;
+    let Header { contents_offset, len, .. } = read_header(&mut file, filename)?;
+    file.seek(SeekFrom::Start(contents_offset))
+        .in_index_file(filename, Section::Contents, contents_offset)?;
+    let mut contents = BufReader::new(file);
+    let mut entries = vec![];
+    let mut position = contents_offset;
+    while let Some(entry) = IndexFileReader::read_entry(&mut contents, filename, &mut position, len)? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// What the header of an index file says, along with the size of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// The magic number the file starts with; always `MAGIC`.
+    pub magic: [u8; 4],
+
+    /// The file format version; always `FORMAT_VERSION`.
+    pub version: u32,
+
+    /// Where in the file the table of contents starts.
+    pub contents_offset: u64,
+
+    /// The size of the file, in bytes.
+    pub len: u64,
+}
+
+/// Read the header of the index file `filename`, open as `file`.
+///
+/// Files that aren't index files, and index files in a format other than
+/// `FORMAT_VERSION`, are reported as damaged.
+pub fn read_header(file: &mut File, filename: &Path) -> io::Result<Header> {
+    let corrupt = |offset: u64, what: String| -> io::Error {
+        Error::Corrupt {
+            path: filename.to_path_buf(),
+            position: Some(Position::in_index(Section::Header, offset)),
+            what,
+        }
+        .into()
+    };
+
+    let mut magic = [0; MAGIC.len()];
+    file.read_exact(&mut magic).in_index_file(filename, Section::Header, 0)?;
+    if &magic != MAGIC {
+        return Err(corrupt(
+            0,
+            "not an index file, or one written by an older version of fingertips \
+             (the index has to be rebuilt)"
+                .to_string(),
+        ));
+    }
+    let version_position = MAGIC.len() as u64;
+    let version = file
+        .read_u32::<LittleEndian>()
+        .in_index_file(filename, Section::Header, version_position)?;
+    if version != FORMAT_VERSION {
+        return Err(corrupt(
+            version_position,
+            format!("index file format version {version} isn't supported (expected {FORMAT_VERSION})"),
+        ));
+    }
+    let contents_offset = file
+        .read_u64::<LittleEndian>()
+        .in_index_file(filename, Section::Header, CONTENTS_OFFSET_POSITION)?;
+    let len = file.metadata().in_file(filename)?.len();
+    if contents_offset < HEADER_SIZE || contents_offset > len {
+        return Err(corrupt(
+            CONTENTS_OFFSET_POSITION,
+            format!("table of contents offset {contents_offset} is out of range"),
+        ));
+    }
+    Ok(Header { magic, version, contents_offset, len })
+}
+
+/// Read a single `Hit` from `reader`, which has `limit` bytes left of the
+/// term's data. The hit's size comes from the data, so it's checked against
+/// `limit` before any room is made for it; a hit that's too big is an
+/// `InvalidData` error.
+pub fn read_hit<R: Read>(reader: &mut R, limit: u64) -> io::Result<Hit> {
+    let (header, len) = read_hit_header(reader, limit)?;
+    let mut hit = header.to_vec();
+    hit.resize(len as usize, 0);
+    reader.read_exact(&mut hit[HIT_HEADER_SIZE..])?;
+    Ok(hit)
+}
+
+/// Read just the document id and count at the start of a `Hit` from
+/// `reader`, which has `limit` bytes left of the term's data, leaving the
+/// offsets to be read, copied or skipped. Returns the header and the size of
+/// the whole hit, which is checked against `limit` the same way as in
+/// `read_hit`.
+pub fn read_hit_header<R: Read>(reader: &mut R, limit: u64) -> io::Result<([u8; HIT_HEADER_SIZE], u64)> {
+    let too_big = || io::Error::new(io::ErrorKind::InvalidData, "hit runs past the end of the term's data");
+    if limit < HIT_HEADER_SIZE as u64 {
+        return Err(too_big());
+    }
+    let mut header = [0; HIT_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let count = LittleEndian::read_u32(&header[4..8]) as u64;
+    let len = HIT_HEADER_SIZE as u64 + 4 * count;
+    if len > limit {
+        return Err(too_big());
+    }
+    Ok((header, len))
+}
+
+/// Read exactly `buf.len()` bytes from `file`, starting `offset` bytes in.
+///
+/// On Unix, this doesn't use or change the file's position, so a file can be
+/// shared. Elsewhere it seeks, so the file's position moves, and two threads
+/// mustn't do this to the same file at once.
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(not(unix))]
+    {
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
+
+/// Reads the `nbytes` bytes of `file` starting `offset` bytes in, like the
+/// data for one term, a buffer at a time, so that a term with millions of hits
+/// can be read through without loading it all into memory. The reads are done
+/// with `read_exact_at`, so the file can be shared the same way.
+pub struct RangeReader<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl<'a> RangeReader<'a> {
+    pub fn new(file: &'a File, offset: u64, nbytes: u64) -> BufReader<RangeReader<'a>> {
+        BufReader::new(RangeReader { file, offset, end: offset.saturating_add(nbytes) })
+    }
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.end - self.offset).min(buf.len() as u64) as usize;
+        read_exact_at(self.file, &mut buf[..n], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
 }
@@ -0,0 +1,197 @@
+//! Searching an index.
+//!
+//! An `Index` is the current generation of a published index, opened for
+//! searching. The tables of contents of its segments, and its document table,
+//! are loaded into memory; the hits for a term are read from disk when the
+//! term is looked up, from every segment that has it. Deleted documents are
+//! skipped.
+//!
+//! `print_matches` does the work of `fingertips search`, with `parse_query`
+//! to check what the user asked for.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::docs::DocumentTable;
+use crate::error::{Context, Error, Position, Section};
+use crate::index::{hit_doc_id, hit_offsets};
+use crate::paths::IndexPaths;
+use crate::publish::Manifest;
+use crate::source::FIELD_NAMES;
+use crate::read::{read_contents, read_hit, Entry, RangeReader};
+
+/// An index, open for searching.
+pub struct Index {
+    /// The segments, in document order, oldest first.
+    segments: Vec<Segment>,
+
+    table: DocumentTable,
+}
+
+/// One of the index data files that make up an index.
+struct Segment {
+    path: PathBuf,
+
+    data: File,
+
+    /// The size of `data`, in bytes.
+    len: u64,
+
+    /// The table of contents of `data`, sorted by term.
+    contents: Vec<Entry>,
+}
+
+/// A document that contains the term being searched for.
+pub struct Match {
+    pub doc_id: usize,
+
+    /// Where the term appears in the document, as word offsets.
+    pub offsets: Vec<u32>,
+}
+
+impl Index {
+    /// Open the current generation of the index named by `paths`.
+    pub fn open(paths: &IndexPaths) -> io::Result<Index> {
+        let manifest = Manifest::load_existing(paths)?;
+        let segments = manifest
+            .segments
+            .iter()
+            .map(|(_, path)| {
+                let data = File::open(path).in_file(path)?;
+                Ok(Segment {
+                    path: path.clone(),
+                    len: data.metadata().in_file(path)?.len(),
+                    data,
+                    contents: read_contents(path)?,
+                })
+            })
+            .collect::<io::Result<Vec<Segment>>>()?;
+        Ok(Index {
+            segments,
+            table: manifest.read_table(paths)?,
+        })
+    }
+
+    /// The document table, for looking up the names of matching documents.
+    pub fn table(&self) -> &DocumentTable {
+        &self.table
+    }
+
+    /// Find the documents that contain `term`, in document id order.
+    ///
+    /// Each segment's documents all come after the previous segment's, so the
+    /// matches from each segment can simply be concatenated.
+    pub fn search(&self, term: &str) -> io::Result<Vec<Match>> {
+        let mut matches = vec![];
+        for segment in &self.segments {
+            let contents = &segment.contents;
+            let Ok(i) = contents.binary_search_by(|entry| entry.term.as_str().cmp(term)) else {
+                continue;
+            };
+            let entry = &contents[i];
+            if entry.offset.checked_add(entry.nbytes).is_none_or(|end| end > segment.len) {
+                return Err(Error::Corrupt {
+                    path: segment.path.clone(),
+                    position: Some(Position::in_index(Section::Contents, entry.offset)),
+                    what: format!("the data for {term:?} runs past the end of the file"),
+                }
+                .into());
+            }
+            let mut hits = RangeReader::new(&segment.data, entry.offset, entry.nbytes);
+            let mut remaining = entry.nbytes;
+            while remaining > 0 {
+                let offset = entry.offset + (entry.nbytes - remaining);
+                let hit = read_hit(&mut hits, remaining)
+                    .in_index_file(&segment.path, Section::Postings, offset)?;
+                remaining -= hit.len() as u64;
+                let doc_id = hit_doc_id(&hit);
+                if !self.table.is_deleted(doc_id) {
+                    matches.push(Match { doc_id, offsets: hit_offsets(&hit).collect() });
+                }
+            }
+        }
+        Ok(matches)
+    }
+}
+
+/// Turn what the user asked to search for into the term to look up: a single
+/// word, or `field:word` to search one of the fields documents can have.
+pub fn parse_query(query: &str) -> Result<String, Error> {
+    let (field, word) = match query.split_once(':') {
+        Some((field, word)) => (Some(field), word),
+        None => (None, query),
+    };
+    if let Some(field) = field
+        && !FIELD_NAMES.contains(&field.to_lowercase().as_str())
+    {
+        return Err(Error::Usage(format!(
+            "can't search for {query:?}: there's no field named {field:?} (the fields are {})",
+            FIELD_NAMES.join(", "),
+        )));
+    }
+    if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+        return Err(Error::Usage(format!(
+            "can't search for {query:?}: only single words, made of letters and digits, are indexed"
+        )));
+    }
+    Ok(query.to_lowercase())
+}
+
+/// Look up `query` (see `parse_query`) in the index named by `paths`, and
+/// print the documents that contain it, with the number of times it appears
+/// in each.
+pub fn print_matches(paths: &IndexPaths, query: &str) -> Result<(), Error> {
+    let term = parse_query(query)?;
+    let index = Index::open(paths)?;
+    let table = index.table();
+    let mut out = BufWriter::new(io::stdout().lock());
+    for m in index.search(&term)? {
+        match table.location(m.doc_id) {
+            Some(location) => writeln!(out, "{}:{}\t{}", table.name(m.doc_id), location.line, m.offsets.len())?,
+            None => writeln!(out, "{}\t{}", table.name(m.doc_id), m.offsets.len())?,
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::memory::DEFAULT_MEMORY_LIMIT;
+    use crate::paths::DEFAULT_INDEX_NAME;
+    use crate::tests::{build, options, test_dir};
+
+    #[test]
+    fn search_reads_through_large_hits() {
+        let dir = test_dir("search-large-hits");
+        let big = dir.join("big.txt");
+        fs::write(&big, "word ".repeat(1_000_000)).unwrap();
+        let small = dir.join("small.txt");
+        fs::write(&small, "other word").unwrap();
+        let output = dir.join("index");
+        build(&[big, small], &output, true, &options(1, DEFAULT_MEMORY_LIMIT), false);
+
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output);
+        let index = Index::open(&paths).unwrap();
+        let matches = index.search("word").unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].offsets.len(), 1_000_000);
+        assert!(matches[0].offsets.iter().enumerate().all(|(i, &offset)| offset as usize == i));
+        assert_eq!((matches[1].doc_id, &matches[1].offsets[..]), (1, &[1][..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_query_accepts_words_and_fields() {
+        assert_eq!(parse_query("Rust").unwrap(), "rust");
+        assert_eq!(parse_query("Title:Rust").unwrap(), "title:rust");
+        assert_eq!(parse_query("subject:2024").unwrap(), "subject:2024");
+        for bad in ["colour:red", "title:", "two words", "title:a:b"] {
+            assert!(matches!(parse_query(bad), Err(Error::Usage(_))), "{bad:?}");
+        }
+    }
+}
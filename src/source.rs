@@ -0,0 +1,372 @@
+//! Reading documents from the filesystem.
+//!
+//! Most documents are plain files, but a single file on disk can also be an
+//! archive or a mailbox containing many documents. This module hides the
+//! difference: give `read_documents` a path, and it produces one `Document`
+//! for each text file or message it finds there.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+
+use crate::docs::Location;
+use crate::error::Context;
+use crate::mail;
+use crate::stamp::{self, HashingReader, PendingStamp};
+
+/// How to turn the files named on the command line into documents.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InputMode {
+    /// Each file is a document, except archives, which contain documents.
+    Files,
+
+    /// Each file is an mbox mailbox, and each directory is a Maildir. Every
+    /// email message is a document.
+    Mail,
+}
+
+/// Plain files at least this big, in bytes, aren't loaded into memory;
+/// instead, the indexer reads them a piece at a time.
+const STREAMING_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// A document, ready to be indexed.
+pub struct Document {
+    /// The name recorded for this document in the document table. For plain
+    /// files, this is just the path. For archive members, it's the path of
+    /// the archive, followed by `!/` and the path of the member within the
+    /// archive, like `corpus.tar.gz!/inner/path.txt`.
+    pub name: String,
+
+    /// The body text of the document.
+    pub body: Body,
+
+    /// Additional named pieces of text, like a title, that are indexed
+    /// separately from the body `text`.
+    pub fields: Vec<Field>,
+
+    /// If this document is only part of a file (see the `split` module),
+    /// where in the file it came from.
+    pub location: Option<Location>,
+
+    /// The stamp of the file this document came from (see the `stamp`
+    /// module), if it came from a file rather than a directory. Its hash is
+    /// filled in once the whole file has been read.
+    pub stamp: Option<PendingStamp>,
+}
+
+/// A named piece of text associated with a document.
+///
+/// The words of a field are indexed as terms of the form `name:word`, so that
+/// searching for `title:rust` finds documents with "Rust" in the title.
+#[derive(Clone)]
+pub struct Field {
+    pub name: &'static str,
+    pub text: String,
+}
+
+/// The names of all the fields a document can have: titles and headings from
+/// marked-up documents (see `extract`), and email headers (see `mail`).
+pub const FIELD_NAMES: &[&str] = &["title", "heading", "from", "to", "subject", "date"];
+
+/// The body text of a document.
+pub enum Body {
+    /// The full text, loaded into memory.
+    Text(String),
+
+    /// A file too big to load into memory all at once. The indexer reads it a
+    /// piece at a time using `InMemoryIndex::from_reader`.
+    File(PathBuf),
+}
+
+impl Document {
+    /// A document with the given name and text, and no extra fields.
+    pub fn new(name: String, text: String) -> Document {
+        Document { name, body: Body::Text(text), fields: vec![], location: None, stamp: None }
+    }
+}
+
+/// The kinds of archives we know how to look inside.
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Guess whether `path` is an archive, based on its name.
+    fn from_path(path: &Path) -> Option<ArchiveKind> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// True if `path` looks like a compressed archive, so that the documents in
+/// it are bigger than the file itself.
+pub fn is_compressed_archive(path: &Path) -> bool {
+    matches!(ArchiveKind::from_path(path), Some(ArchiveKind::TarGz | ArchiveKind::Zip))
+}
+
+/// Load the document or documents stored at `path`, passing each one to `f`.
+///
+/// In `InputMode::Files` mode, if `path` names a tar, tar.gz, or zip archive,
+/// each regular file in the archive is a separate document; the members are
+/// streamed out of the archive one at a time, so the archive never has to be
+/// unpacked on disk. Otherwise, the whole file is one document; if it's
+/// very large, it isn't loaded here, but left to be read as a stream.
+///
+/// In `InputMode::Mail` mode, `path` is a Maildir directory or an mbox file,
+/// and each message in it is a document.
+///
+/// If `path` is a file, every document is stamped with its `PendingStamp`,
+/// started before it's read. The hash is filled in from the bytes read here,
+/// once they've all been read; for a large file left to be read as a stream,
+/// it's up to whatever reads the stream to fill it in. Zip archives are read
+/// out of order, so they're read through once more to hash them.
+///
+/// `f` can return `Ok(ControlFlow::Break(()))` to stop early, in which case
+/// this returns the same without reading any further. Errors from `f` are
+/// passed through to the caller.
+pub fn read_documents<F>(path: &Path, mode: InputMode, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    let stamp = if path.is_file() { Some(PendingStamp::start(path)?) } else { None };
+    let mut f = {
+        let stamp = stamp.clone();
+        move |mut doc: Document| {
+            doc.stamp = stamp.clone();
+            f(doc)
+        }
+    };
+
+    if mode == InputMode::Mail {
+        return if mail::is_maildir(path) {
+            mail::read_maildir(path, f)
+        } else {
+            let mut file = HashingReader::new(File::open(path).in_file(path)?);
+            let flow = mail::read_mbox(path, BufReader::new(&mut file), f)?;
+            finish_stamp(&stamp, flow, || file.finish().in_file(path))
+        };
+    }
+
+    match ArchiveKind::from_path(path) {
+        None => {
+            let name = path.display().to_string();
+            if fs::metadata(path).in_file(path)?.len() >= STREAMING_THRESHOLD {
+                f(Document {
+                    name,
+                    body: Body::File(path.to_owned()),
+                    fields: vec![],
+                    location: None,
+                    stamp: None,
+                })
+            } else {
+                let bytes = fs::read(path).in_file(path)?;
+                let hash = stamp::hash(&bytes);
+                let text = String::from_utf8(bytes)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                    .in_file(path)?;
+                let flow = f(Document::new(name, text))?;
+                finish_stamp(&stamp, flow, || Ok(hash))
+            }
+        }
+        Some(ArchiveKind::Tar) => {
+            let mut file = HashingReader::new(File::open(path).in_file(path)?);
+            let flow = read_tar_members(path, BufReader::new(&mut file), f)?;
+            finish_stamp(&stamp, flow, || file.finish().in_file(path))
+        }
+        Some(ArchiveKind::TarGz) => {
+            let mut file = HashingReader::new(File::open(path).in_file(path)?);
+            let flow = read_tar_members(path, GzDecoder::new(BufReader::new(&mut file)), f)?;
+            finish_stamp(&stamp, flow, || file.finish().in_file(path))
+        }
+        Some(ArchiveKind::Zip) => {
+            let file = BufReader::new(File::open(path).in_file(path)?);
+            let flow = read_zip_members(path, file, f)?;
+            finish_stamp(&stamp, flow, || stamp::hash_file(path))
+        }
+    }
+}
+
+/// Fill in the hash of `stamp`, if there is one, once its file has been read
+/// to the end. If `f` stopped early, the file wasn't read to the end, and
+/// the run is being abandoned anyway, so `hash` isn't called.
+fn finish_stamp<H>(stamp: &Option<PendingStamp>, flow: ControlFlow<()>, hash: H) -> io::Result<ControlFlow<()>>
+where
+    H: FnOnce() -> io::Result<u64>,
+{
+    if let (Some(stamp), ControlFlow::Continue(())) = (stamp, flow) {
+        stamp.finish(hash()?);
+    }
+    Ok(flow)
+}
+
+/// The document name for the member `inner` of the archive at `archive`.
+fn member_name(archive: &Path, inner: &str) -> String {
+    format!("{}!/{}", archive.display(), inner)
+}
+
+fn read_tar_members<R, F>(path: &Path, reader: R, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    R: Read,
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().in_file(path)? {
+        let mut entry = entry.in_file(path)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = member_name(path, &entry.path().in_file(path)?.to_string_lossy());
+        let mut text = String::new();
+        entry.read_to_string(&mut text).in_file(Path::new(&name))?;
+        if f(Document::new(name, text))?.is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+fn read_zip_members<R, F>(path: &Path, reader: R, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    R: Read + io::Seek,
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    let mut archive = zip::ZipArchive::new(reader).map_err(io::Error::from).in_file(path)?;
+    for i in 0..archive.len() {
+        let mut member = archive.by_index(i).map_err(io::Error::from).in_file(path)?;
+        if !member.is_file() {
+            continue;
+        }
+        let name = member_name(path, member.name());
+        let mut text = String::new();
+        member.read_to_string(&mut text).in_file(Path::new(&name))?;
+        if f(Document::new(name, text))?.is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::SimpleFileOptions;
+
+    use crate::tests::test_dir;
+
+    /// The name and text of every document `read_documents` finds at `path`.
+    fn read_all(path: &Path) -> Vec<(String, String)> {
+        let mut docs = vec![];
+        let flow = read_documents(path, InputMode::Files, |doc| {
+            let Body::Text(text) = doc.body else { panic!("{} wasn't loaded into memory", doc.name) };
+            docs.push((doc.name, text));
+            Ok(ControlFlow::Continue(()))
+        });
+        assert!(flow.unwrap().is_continue());
+        docs
+    }
+
+    #[test]
+    fn tar_gz_and_zip_members() {
+        let dir = test_dir("archive-members");
+
+        let tar_gz = dir.join("corpus.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(&tar_gz).unwrap(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, "inner/", io::empty()).unwrap();
+        for (name, text) in [("inner/path.txt", "deep in the archive"), ("top.txt", "at the top")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, text.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let zip = dir.join("corpus.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip).unwrap());
+        writer.add_directory("inner/", SimpleFileOptions::default()).unwrap();
+        writer.start_file("inner/path.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"zipped and deflated").unwrap();
+        writer.finish().unwrap();
+
+        // Directories aren't documents; every regular file is one, named
+        // after the archive and its path inside it.
+        let archive = tar_gz.display();
+        assert_eq!(
+            read_all(&tar_gz),
+            vec![
+                (format!("{archive}!/inner/path.txt"), "deep in the archive".to_string()),
+                (format!("{archive}!/top.txt"), "at the top".to_string()),
+            ]
+        );
+        assert_eq!(
+            read_all(&zip),
+            vec![(format!("{}!/inner/path.txt", zip.display()), "zipped and deflated".to_string())]
+        );
+
+        // Anything else is a single document, named by its path.
+        let plain = dir.join("plain.txt");
+        fs::write(&plain, "just text").unwrap();
+        assert_eq!(read_all(&plain), vec![(plain.display().to_string(), "just text".to_string())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stamps_are_hashed_from_the_bytes_read() {
+        let dir = test_dir("stamp-hashes");
+
+        let plain = dir.join("plain.txt");
+        fs::write(&plain, "just text").unwrap();
+
+        let tar = dir.join("corpus.tar");
+        let mut builder = tar::Builder::new(File::create(&tar).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "a.txt", &b"text"[..]).unwrap();
+        builder.into_inner().unwrap();
+
+        let mbox = dir.join("mail.mbox");
+        fs::write(&mbox, "From a\nSubject: one\n\nfirst\n\nFrom b\nSubject: two\n\nsecond\n").unwrap();
+
+        for (path, mode) in [(&plain, InputMode::Files), (&tar, InputMode::Files), (&mbox, InputMode::Mail)] {
+            let mut stamps = vec![];
+            let flow = read_documents(path, mode, |doc| {
+                stamps.push(doc.stamp.unwrap());
+                Ok(ControlFlow::Continue(()))
+            });
+            assert!(flow.unwrap().is_continue());
+            assert!(!stamps.is_empty());
+
+            // Once the whole file has been read, every document has its stamp,
+            // with the same hash as reading the file again.
+            let size = fs::metadata(path).unwrap().len();
+            let hash = stamp::hash_file(path).unwrap();
+            for stamp in stamps {
+                let stamp = stamp.get().unwrap();
+                assert!(stamp.size == size && stamp.hash == hash, "{}", path.display());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
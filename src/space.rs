@@ -0,0 +1,172 @@
+//! Checking for enough disk space before building an index.
+//!
+//! Running out of disk space halfway through a big build wastes all the time
+//! spent so far. So before starting, we estimate how much space the build
+//! will need, and compare that to how much is free.
+//!
+//! The estimate is rough. An index is usually a bit bigger than the text it
+//! indexes. While building it, the temporary files hold about that much data,
+//! and merging them takes twice that, since a merge's inputs aren't deleted
+//! until its output is complete. The last merge is the biggest. Its output
+//! becomes the index, so if the temporary files are on the same filesystem as
+//! the index, that's all the space we need; otherwise the index needs its own
+//! space for a copy. (With a very small `--memory-limit`, there are so many
+//! temporary files, repeating so many of the same terms, that they can take
+//! up more than this.)
+//!
+//! Finding out how much space is free takes a system call that only Unix
+//! has. Elsewhere, the check always passes.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context, Error};
+use crate::paths::IndexPaths;
+use crate::progress::format_bytes;
+use crate::source::is_compressed_archive;
+
+/// What to do if there may not be enough disk space.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SpaceCheck {
+    /// Stop with an error, before doing any work.
+    Fail,
+
+    /// Print a warning and carry on.
+    Warn,
+
+    /// Don't check.
+    Off,
+}
+
+impl SpaceCheck {
+    /// Parse the argument of the `--space-check` command-line option.
+    pub fn parse(s: &str) -> Result<SpaceCheck, Error> {
+        match s {
+            "fail" => Ok(SpaceCheck::Fail),
+            "warn" => Ok(SpaceCheck::Warn),
+            "none" | "off" => Ok(SpaceCheck::Off),
+            _ => Err(Error::Usage(format!(
+                "unknown space check mode {s:?} (expected fail, warn, or none)"
+            ))),
+        }
+    }
+}
+
+/// Roughly how big an index is, compared to the text it indexes. (It's more
+/// like 1.3 for plain text, but it's better to overestimate.)
+const INDEX_SIZE_RATIO: f64 = 1.5;
+
+/// Roughly how much bigger text gets when it's decompressed.
+const COMPRESSION_RATIO: u64 = 4;
+
+/// Check that there's enough free space to build an index of `documents`
+/// in the places named by `paths`. When adding to an existing index,
+/// `base_size` is the total size of its segments, which may get merged into
+/// new ones; otherwise it's 0. What happens if there isn't enough space is up
+/// to `check`.
+pub fn check_space(
+    paths: &IndexPaths,
+    documents: &[PathBuf],
+    base_size: u64,
+    check: SpaceCheck,
+) -> io::Result<()> {
+    if check == SpaceCheck::Off {
+        return Ok(());
+    }
+
+    let new_size = estimate_index_size(documents);
+    let index_size = new_size + base_size;
+    let mut needs = vec![(paths.tmp_dir(), new_size + index_size, "temporary files")];
+    if device(paths.dir())? == device(paths.tmp_dir())? {
+        needs[0].2 = "temporary files and the index";
+    } else {
+        needs.push((paths.dir(), index_size, "the index"));
+    }
+
+    for (dir, needed, what) in needs {
+        let available = available_space(dir)?;
+        if available >= needed {
+            continue;
+        }
+        let message = format!(
+            "there may not be enough disk space: {what} need about {} in {}, \
+             but only {} is free",
+            format_bytes(needed),
+            dir.display(),
+            format_bytes(available),
+        );
+        match check {
+            SpaceCheck::Fail => {
+                return Err(Error::NoSpace(format!(
+                    "{message} (use --space-check=warn to try anyway)"
+                ))
+                .into());
+            }
+            SpaceCheck::Warn => eprintln!("warning: {message}"),
+            SpaceCheck::Off => {}
+        }
+    }
+    Ok(())
+}
+
+/// Guess how big the index of `documents` will be, in bytes.
+fn estimate_index_size(documents: &[PathBuf]) -> u64 {
+    let text_size: u64 = documents
+        .iter()
+        .map(|path| {
+            let size = fs::metadata(path).map_or(0, |m| if m.is_file() { m.len() } else { 0 });
+            if is_compressed_archive(path) {
+                size * COMPRESSION_RATIO
+            } else {
+                size
+            }
+        })
+        .sum();
+    (text_size as f64 * INDEX_SIZE_RATIO) as u64
+}
+
+/// The device number of the filesystem containing `dir`.
+#[cfg(unix)]
+fn device(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(fs::metadata(dir).in_file(dir)?.dev())
+}
+
+/// Without device numbers, assume everything is on one filesystem. (It
+/// doesn't matter much, since `available_space` doesn't know either.)
+#[cfg(not(unix))]
+fn device(dir: &Path) -> io::Result<u64> {
+    fs::metadata(dir).in_file(dir)?;
+    Ok(0)
+}
+
+/// The number of bytes free for ordinary users on the filesystem containing
+/// `dir`.
+#[cfg(unix)]
+fn available_space(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other).in_file(dir)?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string, and `stats` has room for the struct
+    // that `statvfs` fills in.
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).in_file(dir);
+    }
+    // SAFETY: `statvfs` succeeded, so it filled in `stats`.
+    let stats = unsafe { stats.assume_init() };
+    // These fields are 32 bits on some platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// There's no portable way to ask, so as far as the check is concerned,
+/// there's always enough.
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
@@ -0,0 +1,197 @@
+//! Splitting files into smaller documents.
+//!
+//! When searching logs, a hit that says "somewhere in this 2GB file" isn't
+//! much help. So optionally, each line of a file, or each multi-line record,
+//! can be indexed as a separate document. The document table then records
+//! where in the file each one came from.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::docs::Location;
+use crate::error::Context;
+use crate::source::{Body, Document};
+use crate::stamp::HashingReader;
+
+/// How to split a document into smaller documents.
+#[derive(Clone)]
+pub enum Splitter {
+    /// Every line is a separate document.
+    Lines,
+
+    /// Each line that matches the regular expression starts a new document,
+    /// which continues up to the next matching line. This is for logs where a
+    /// single entry can span several lines, like a stack trace.
+    Records(Regex),
+}
+
+impl Splitter {
+    /// True if `line` should start a new document.
+    fn starts_record(&self, line: &str) -> bool {
+        match self {
+            Splitter::Lines => true,
+            Splitter::Records(re) => re.is_match(line),
+        }
+    }
+}
+
+/// Size of the buffer used when splitting a file that's being read as a
+/// stream.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Split `doc` into pieces, passing each piece to `f` as a separate document.
+///
+/// Each piece has the same name, fields and stamp as `doc`, plus a `Location`
+/// giving its first line number and its byte range within `doc`. Pieces that
+/// contain nothing but whitespace are dropped.
+///
+/// Documents that were too big to load into memory are read a line at a time,
+/// so only one record needs to be in memory at once, and their stamps' hashes
+/// are filled in as they're read.
+pub fn split_document<F>(doc: Document, splitter: &Splitter, mut f: F) -> io::Result<ControlFlow<()>>
+where
+    F: FnMut(Document) -> io::Result<ControlFlow<()>>,
+{
+    let Document { name, body, fields, stamp, .. } = doc;
+    let mut emit = |text: String, location: Location| {
+        if text.trim().is_empty() {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let mut piece = Document::new(name.clone(), text);
+        piece.fields = fields.clone();
+        piece.location = Some(location);
+        piece.stamp = stamp.clone();
+        f(piece)
+    };
+
+    match body {
+        Body::Text(text) => split_lines(text.as_bytes(), Path::new(&name), splitter, &mut emit),
+        Body::File(path) => {
+            let mut file = HashingReader::new(File::open(&path).in_file(&path)?);
+            let flow = split_lines(BufReader::with_capacity(BUFFER_SIZE, &mut file), &path, splitter, &mut emit)?;
+            if let (Some(stamp), ControlFlow::Continue(())) = (&stamp, flow) {
+                stamp.finish(file.finish().in_file(&path)?);
+            }
+            Ok(flow)
+        }
+    }
+}
+
+/// Read lines from `reader`, which reads the document `path`, grouping them
+/// into records according to `splitter`, and pass each record to `emit`.
+fn split_lines<R, F>(
+    mut reader: R,
+    path: &Path,
+    splitter: &Splitter,
+    emit: &mut F,
+) -> io::Result<ControlFlow<()>>
+where
+    R: BufRead,
+    F: FnMut(String, Location) -> io::Result<ControlFlow<()>>,
+{
+    let mut record = String::new();
+    let mut location = Location { line: 1, start: 0, end: 0 };
+    let mut line_number = 0;
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let nbytes = reader.read_until(b'\n', &mut line).in_file(path)?;
+        if nbytes == 0 {
+            break;
+        }
+        line_number += 1;
+        let text = match String::from_utf8(std::mem::take(&mut line)) {
+            Ok(text) => text,
+            Err(_) => {
+                let message = format!("line {line_number} isn't valid UTF-8");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message)).in_file(path);
+            }
+        };
+
+        if splitter.starts_record(&text) && !record.is_empty() {
+            if emit(std::mem::take(&mut record), location)?.is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+            location = Location { line: line_number, start: location.end, end: location.end };
+        }
+        location.end += nbytes as u64;
+        record.push_str(&text);
+        line = text.into_bytes();
+    }
+
+    if record.is_empty() {
+        Ok(ControlFlow::Continue(()))
+    } else {
+        emit(record, location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split `text`, returning each piece's text, first line, and byte range.
+    fn split(text: &str, splitter: &Splitter) -> Vec<(String, u64, u64, u64)> {
+        let mut pieces = vec![];
+        let doc = Document::new("log".to_string(), text.to_string());
+        let flow = split_document(doc, splitter, |piece| {
+            let Body::Text(text) = piece.body else { unreachable!() };
+            let Location { line, start, end } = piece.location.unwrap();
+            pieces.push((text, line, start, end));
+            Ok(ControlFlow::Continue(()))
+        })
+        .unwrap();
+        assert!(flow.is_continue());
+        pieces
+    }
+
+    #[test]
+    fn lines() {
+        let text = "one\ntwo\n   \nthree";
+        assert_eq!(
+            split(text, &Splitter::Lines),
+            [
+                ("one\n".to_string(), 1, 0, 4),
+                ("two\n".to_string(), 2, 4, 8),
+                // The blank line 3 is dropped, but still counted.
+                ("three".to_string(), 4, 12, 17),
+            ],
+        );
+    }
+
+    #[test]
+    fn records() {
+        let text = "\nERROR boom\n  at foo\n  at bar\nINFO ok\nERROR again\n";
+        let splitter = Splitter::Records(Regex::new("^[A-Z]+ ").unwrap());
+        let pieces = split(text, &splitter);
+        assert_eq!(
+            pieces,
+            [
+                ("ERROR boom\n  at foo\n  at bar\n".to_string(), 2, 1, 30),
+                ("INFO ok\n".to_string(), 5, 30, 38),
+                ("ERROR again\n".to_string(), 6, 38, 50),
+            ],
+        );
+        // Each location's byte range is exactly the piece's text.
+        for (piece, _, start, end) in &pieces {
+            assert_eq!(&text[*start as usize..*end as usize], piece);
+        }
+    }
+
+    #[test]
+    fn break_stops_splitting() {
+        let mut count = 0;
+        let doc = Document::new("log".to_string(), "a\nb\nc\n".to_string());
+        let flow = split_document(doc, &Splitter::Lines, |_| {
+            count += 1;
+            Ok(ControlFlow::Break(()))
+        })
+        .unwrap();
+        assert!(flow.is_break());
+        assert_eq!(count, 1);
+    }
+}
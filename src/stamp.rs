@@ -0,0 +1,168 @@
+//! Noticing which files have changed since they were indexed.
+//!
+//! Each document in the document table has a *stamp*: the modification time,
+//! size, and a hash of the contents of the file it came from. The time and
+//! size are taken just before the file is read, and the hash is worked out
+//! from the bytes read for indexing, so indexing doesn't read anything twice.
+//! (Every document in an archive or mailbox has the stamp of the whole file.)
+//! To find out whether a file needs indexing again,
+//! compare it with its stamp. The modification time and size are enough to
+//! tell that most files haven't changed, without reading them. If only the
+//! modification time is different, as when a file is touched or checked out
+//! again, the file is read to compare its hash.
+//!
+//! The hash is 64-bit FNV-1a. It's not meant to stand up to anyone trying to
+//! fool it, only to notice edits.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use crate::error::Context;
+
+/// What a file looked like when it was indexed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    /// Modification time, in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+
+    /// Size in bytes.
+    pub size: u64,
+
+    /// Hash of the contents.
+    pub hash: u64,
+}
+
+/// How a file compares with its stamp.
+pub enum Change {
+    /// Same modification time and size.
+    Unchanged,
+
+    /// Different modification time, but the same contents. The new stamp is
+    /// worth saving, to save rereading the file next time.
+    Touched(FileStamp),
+
+    /// Different contents.
+    Modified,
+}
+
+impl FileStamp {
+    /// Find out whether the file `path` has changed since this stamp was taken.
+    pub fn compare(&self, path: &Path) -> io::Result<Change> {
+        let (mtime, size) = stat(path)?;
+        if size != self.size {
+            return Ok(Change::Modified);
+        }
+        if mtime == self.mtime {
+            return Ok(Change::Unchanged);
+        }
+        let hash = hash_file(path)?;
+        if hash == self.hash {
+            Ok(Change::Touched(FileStamp { mtime, size, hash }))
+        } else {
+            Ok(Change::Modified)
+        }
+    }
+}
+
+/// The stamp of a file that's being indexed. The modification time and size
+/// are taken before the file is read; the hash is filled in by `finish`, once
+/// whatever is reading the file for indexing has hashed all of it. Clones
+/// share the hash, so every document from the file gets it.
+#[derive(Clone)]
+pub struct PendingStamp {
+    mtime: u64,
+    size: u64,
+    hash: Arc<OnceLock<u64>>,
+}
+
+impl PendingStamp {
+    /// Start the stamp of the file `path`, before reading it.
+    pub fn start(path: &Path) -> io::Result<PendingStamp> {
+        let (mtime, size) = stat(path)?;
+        Ok(PendingStamp { mtime, size, hash: Arc::new(OnceLock::new()) })
+    }
+
+    /// Fill in the hash of the file's contents.
+    pub fn finish(&self, hash: u64) {
+        let _ = self.hash.set(hash);
+    }
+
+    /// The stamp, if the hash has been filled in. It isn't if the file was
+    /// never read to the end, as when resuming a run that had already indexed
+    /// a large file; such documents are saved without a stamp, and `update`
+    /// indexes them again.
+    pub fn get(&self) -> Option<FileStamp> {
+        let hash = *self.hash.get()?;
+        Some(FileStamp { mtime: self.mtime, size: self.size, hash })
+    }
+}
+
+impl From<FileStamp> for PendingStamp {
+    fn from(stamp: FileStamp) -> PendingStamp {
+        PendingStamp { mtime: stamp.mtime, size: stamp.size, hash: Arc::new(OnceLock::from(stamp.hash)) }
+    }
+}
+
+/// A reader that hashes everything read through it, for stamping a file as
+/// it's read for indexing.
+pub struct HashingReader<R> {
+    inner: R,
+    hash: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader { inner, hash: OFFSET_BASIS }
+    }
+
+    /// Read whatever is left of the input, so that the hash covers all of it
+    /// (readers for archives can stop short of the end), and return the hash.
+    pub fn finish(mut self) -> io::Result<u64> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.hash)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash = hash_bytes(self.hash, &buf[..n]);
+        Ok(n)
+    }
+}
+
+/// The hash of `bytes`, the whole contents of a file.
+pub fn hash(bytes: &[u8]) -> u64 {
+    hash_bytes(OFFSET_BASIS, bytes)
+}
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0100_0000_01b3;
+
+/// Carry on the hash `hash` over `bytes`.
+fn hash_bytes(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash = (hash ^ byte as u64).wrapping_mul(PRIME);
+    }
+    hash
+}
+
+/// The modification time and size of the file `path`.
+fn stat(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path).in_file(path)?;
+    let mtime = metadata
+        .modified()
+        .in_file(path)?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    Ok((mtime, metadata.len()))
+}
+
+/// Hash the contents of the file `path`.
+pub fn hash_file(path: &Path) -> io::Result<u64> {
+    HashingReader::new(File::open(path).in_file(path)?).finish().in_file(path)
+}
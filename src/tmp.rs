@@ -1,41 +1,195 @@
+//! Temporary index files.
+//!
+//! While building an index, we write many temporary files, which are merged
+//! and deleted as we go. If indexing fails or is interrupted partway through,
+//! whatever temporary files are left over would clutter up the output
+//! directory forever. So `TmpDir` keeps track of every file it creates, and
+//! when it's dropped, deletes any that are still there.
+//!
+//! That doesn't help if the program crashes, or is killed. So temporary files
+//! are named after the index they're for, `NAME.tmp00000001.dat` and so on,
+//! and the next run on the same index deletes any it finds that its
+//! checkpoint doesn't need (see `leftover_files`). Only one program at a time
+//! changes an index (see `lock`), so these can't belong to anyone else.
+
 use std::io::{self, BufWriter};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Context;
 
+/// A source of temporary filenames in a particular directory.
+///
+/// Cloning a `TmpDir` is cheap, and all the clones share the same list of
+/// files. The files are cleaned up when the last clone is dropped.
 #[derive(Clone)]
 pub struct TmpDir {
     dir: PathBuf,
+    name: String,
+    state: Arc<Mutex<TmpFiles>>,
+}
+
+struct TmpFiles {
+    /// The number to try next when making up a filename.
     n: usize,
+
+    /// Every file we've created. Most of them will already have been deleted
+    /// or renamed by the time we're dropped, which is fine.
+    created: Vec<PathBuf>,
+
+    /// Total size of the temporary files that currently exist, as far as we
+    /// know, and the most that's ever been.
+    usage: u64,
+    peak_usage: u64,
 }
 
 impl TmpDir {
-    pub fn new<P: AsRef<Path>>(dir: P) -> TmpDir {
-        TmpDir {
-            dir: dir.as_ref().to_owned(),
-            n: 1,
-        }
+    /// Make temporary files in `dir` for the index named `name`.
+    ///
+    /// Files from earlier runs may still be there, either because they're
+    /// in the checkpoint or because they were kept with `--keep-merged`. New
+    /// files are numbered from just past the highest number in use, rather
+    /// than trying each name that's taken in turn.
+    pub fn new<P: AsRef<Path>>(dir: P, name: &str) -> io::Result<TmpDir> {
+        let dir = dir.as_ref().to_owned();
+        let highest = leftover_files(&dir, name)?
+            .iter()
+            .filter_map(|path| tmp_file_number(path.file_name()?.to_str()?, name))
+            .max()
+            .unwrap_or(0);
+        Ok(TmpDir {
+            dir,
+            name: name.to_string(),
+            state: Arc::new(Mutex::new(TmpFiles {
+                n: highest.saturating_add(1),
+                created: vec![],
+                usage: 0,
+                peak_usage: 0,
+            })),
+        })
     }
 
-    pub fn create(&mut self) -> io::Result<(PathBuf, BufWriter<File>)> {
+    pub fn create(&self) -> io::Result<(PathBuf, BufWriter<File>)> {
+        let mut state = self.state.lock().unwrap();
         let mut attempt = 1;
         loop {
-            let filename = self.dir.join(PathBuf::from(format!("tmp{:08x}.dat", self.n)));
-            self.n += 1;
+            let filename = self.dir.join(format!("{}.tmp{:08x}.dat", self.name, state.n));
+            state.n += 1;
             match fs::OpenOptions::new()
                 .write(true)
                 .create_new(true)
                 .open(&filename)
             {
-                Ok(f) =>
-                    return Ok((filename, BufWriter::new(f))),
+                Ok(f) => {
+                    state.created.push(filename.clone());
+                    return Ok((filename, BufWriter::new(f)));
+                }
                 Err(exc) =>
                     if attempt < 999 && exc.kind() == io::ErrorKind::AlreadyExists {
                         // keep going
                     } else {
-                        return Err(exc);
+                        return Err(exc).in_file(&filename);
                     }
             }
             attempt += 1;
         }
     }
+
+    /// Record that a temporary file of `bytes` bytes has been written, for
+    /// the purpose of measuring how much disk space temporary files use.
+    pub fn add_usage(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.usage += bytes;
+        state.peak_usage = state.peak_usage.max(state.usage);
+    }
+
+    /// The most disk space that temporary files have used at any one time.
+    pub fn peak_usage(&self) -> u64 {
+        self.state.lock().unwrap().peak_usage
+    }
+
+    /// Delete temporary files that are no longer needed. Files that are
+    /// already gone are fine.
+    pub fn remove(&self, files: &[PathBuf]) -> io::Result<()> {
+        for file in files {
+            let bytes = fs::metadata(file).map_or(0, |m| m.len());
+            match fs::remove_file(file) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err).in_file(file),
+                _ => {}
+            }
+            let mut state = self.state.lock().unwrap();
+            state.usage = state.usage.saturating_sub(bytes);
+        }
+        Ok(())
+    }
+
+    /// Stop tracking `filename`, so that it's not deleted when this `TmpDir`
+    /// is dropped. This is for files that are saved in a checkpoint, which
+    /// should survive if indexing is interrupted.
+    pub fn keep(&self, filename: &Path) {
+        self.state.lock().unwrap().created.retain(|f| f != filename);
+    }
+}
+
+impl Drop for TmpFiles {
+    fn drop(&mut self) {
+        for filename in &self.created {
+            // Files that were merged or renamed are already gone. Nothing
+            // useful can be done about other errors at this point.
+            let _ = fs::remove_file(filename);
+        }
+    }
+}
+
+/// The temporary files for the index named `name` that are in `dir`, such
+/// as files left behind by a run that crashed.
+pub fn leftover_files(dir: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).in_file(dir)? {
+        let entry = entry.in_file(dir)?;
+        let filename = entry.file_name();
+        if filename.to_str().and_then(|filename| tmp_file_number(filename, name)).is_some() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// If `filename` is the name of one of the temporary files for the index
+/// named `name`, the number in it.
+pub fn tmp_file_number(filename: &str, name: &str) -> Option<usize> {
+    let hex = filename
+        .strip_prefix(name)?
+        .strip_prefix(".tmp")?
+        .strip_suffix(".dat")?;
+    if hex.len() < 8 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Move the file `from` to `to`, replacing `to` if it exists.
+///
+/// This is a rename if possible. But temporary files may be on a different
+/// filesystem from the index, and then renaming doesn't work; instead the file
+/// is copied to a new file next to `to`, which is then renamed into place. So
+/// either way, nobody ever sees a half-written file at `to`.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result.in_file(from),
+    }
+
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let copied = fs::copy(from, &partial)
+        .and_then(|_| File::open(&partial)?.sync_all())
+        .and_then(|_| fs::rename(&partial, to));
+    if let Err(err) = copied {
+        let _ = fs::remove_file(&partial);
+        return Err(err).in_file(&partial);
+    }
+    fs::remove_file(from).in_file(from)
 }
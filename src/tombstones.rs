@@ -0,0 +1,132 @@
+//! Deleted documents.
+//!
+//! Index files are never changed once they're written, so deleting a document
+//! can't remove it from the index right away. Instead, each generation of the
+//! index has a set of *tombstones*: a bitmap, saved as `NAME.N.del`, with a
+//! bit set for each deleted document id. Searches skip deleted documents, and
+//! merges leave them out, so the space they take up is reclaimed the next time
+//! the index is merged.
+//!
+//! Document ids are never reused. A deleted document keeps its entry in the
+//! document table, and its tombstone stays set.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{Context, Error};
+
+/// The set of deleted document ids.
+#[derive(Clone, Default)]
+pub struct Tombstones {
+    /// Bit `i` of `bits[n]` is set if document `64 * n + i` is deleted.
+    bits: Vec<u64>,
+}
+
+impl Tombstones {
+    /// An empty set: no documents are deleted.
+    pub fn new() -> Tombstones {
+        Tombstones::default()
+    }
+
+    /// Load tombstones saved by `write`. If there's no such file, no
+    /// documents are deleted.
+    ///
+    /// The file is simply the bitmap, as a sequence of little-endian u64
+    /// values.
+    pub fn read(filename: &Path) -> io::Result<Tombstones> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Tombstones::new()),
+            Err(err) => return Err(err).in_file(filename),
+        };
+        let len = file.metadata().in_file(filename)?.len();
+        if len % 8 != 0 {
+            return Err(Error::Corrupt {
+                path: filename.to_path_buf(),
+                position: None,
+                what: "tombstone file length isn't a multiple of 8".to_string(),
+            }
+            .into());
+        }
+        let mut input = BufReader::new(file);
+        let bits = (0..len / 8)
+            .map(|_| input.read_u64::<LittleEndian>())
+            .collect::<io::Result<Vec<u64>>>()
+            .in_file(filename)?;
+        Ok(Tombstones { bits })
+    }
+
+    /// Save the tombstones to the file `filename`, and sync it to disk.
+    pub fn write(&self, filename: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename).in_file(filename)?);
+        for &word in &self.bits {
+            out.write_u64::<LittleEndian>(word).in_file(filename)?;
+        }
+        out.into_inner().map_err(io::Error::from).and_then(|f| f.sync_all()).in_file(filename)
+    }
+
+    /// Mark a document as deleted.
+    pub fn delete(&mut self, doc_id: usize) {
+        let (word, bit) = (doc_id / 64, doc_id % 64);
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << bit;
+    }
+
+    /// True if the document `doc_id` has been deleted.
+    pub fn is_deleted(&self, doc_id: usize) -> bool {
+        self.bits
+            .get(doc_id / 64)
+            .is_some_and(|word| word & (1 << (doc_id % 64)) != 0)
+    }
+
+    /// True if no documents are deleted.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&word| word == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::tests::test_dir;
+
+    #[test]
+    fn delete_and_round_trip() {
+        let dir = test_dir("tombstones");
+        let filename = dir.join("index.1.del");
+
+        // No file means nothing is deleted.
+        let empty = Tombstones::read(&filename).unwrap();
+        assert!(empty.is_empty());
+        assert!(!empty.is_deleted(0));
+
+        let mut tombstones = Tombstones::new();
+        for doc_id in [0, 63, 64, 200] {
+            tombstones.delete(doc_id);
+        }
+        assert!(!tombstones.is_empty());
+        tombstones.write(&filename).unwrap();
+
+        let loaded = Tombstones::read(&filename).unwrap();
+        let deleted: Vec<usize> = (0..1000).filter(|&id| loaded.is_deleted(id)).collect();
+        assert_eq!(deleted, [0, 63, 64, 200]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_rejects_bad_length() {
+        let dir = test_dir("tombstones-bad-length");
+        let filename = dir.join("index.1.del");
+        fs::write(&filename, [0xff; 12]).unwrap();
+        let err = Tombstones::read(&filename).err().unwrap();
+        assert!(matches!(Error::from(err), Error::Corrupt { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+//! Keeping an existing index up to date with the files it was made from:
+//! reindexing the ones that changed, once or whenever they change, and
+//! deleting documents.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{expand_filename_arguments, run, IndexOptions, RunOptions};
+use crate::source::InputMode;
+use crate::progress::ProgressMode;
+use crate::cancel::Cancel;
+use crate::paths::IndexPaths;
+use crate::publish::{publish, Manifest};
+use crate::stamp::Change;
+use crate::watch::Watcher;
+use crate::error::Error;
+
+/// Bring the index named by `paths` up to date with the files and directories
+/// named `filenames`: index the files that are new or have changed since they
+/// were indexed, and delete the documents from files that are gone.
+///
+/// Files whose documents have stamps that match them are left alone. Removed
+/// files and touched files' new stamps are published first, as a generation of
+/// their own; then the new and changed files are added to the index, the same
+/// as with `--add`.
+pub fn run_update(
+    filenames: Vec<String>,
+    paths: IndexPaths,
+    options: IndexOptions,
+    run_options: RunOptions,
+    interrupt: &Cancel,
+) -> Result<(), Error> {
+    if options.mode == InputMode::Mail {
+        return Err(Error::Usage("--update doesn't work with --mail".to_string()));
+    }
+    let manifest = Manifest::load_existing(&paths)?;
+    let mut table = manifest.read_table(&paths)?;
+    let documents = expand_filename_arguments(filenames.clone(), options.mode, &paths)?;
+
+    // Group the documents in the index by the file they came from.
+    let mut indexed: HashMap<String, Vec<usize>> = HashMap::new();
+    for doc_id in (0..table.len()).filter(|&doc_id| !table.is_deleted(doc_id)) {
+        let name = table.name(doc_id);
+        let source = name.split_once("!/").map_or(name, |(archive, _)| archive);
+        indexed.entry(source.to_string()).or_default().push(doc_id);
+    }
+
+    // Compare each file with the stamp of its documents.
+    let mut changed = vec![];
+    let mut touched = 0;
+    for path in documents {
+        let Some(doc_ids) = indexed.remove(&path.display().to_string()) else {
+            changed.push(path);
+            continue;
+        };
+        let change = match table.stamp(doc_ids[0]) {
+            Some(stamp) => stamp.compare(&path)?,
+            None => Change::Modified,
+        };
+        match change {
+            Change::Unchanged => {}
+            Change::Touched(stamp) => {
+                for doc_id in doc_ids {
+                    table.set_stamp(doc_id, stamp);
+                }
+                touched += 1;
+            }
+            Change::Modified => changed.push(path),
+        }
+    }
+
+    // Whatever's left came from files that weren't found. The ones that
+    // should have been, because they were named or are somewhere under a
+    // directory that was named, have been removed.
+    let mut removed = 0;
+    for (source, doc_ids) in indexed {
+        let source = Path::new(&source);
+        let gone = filenames.iter().any(|arg| source.starts_with(arg));
+        if gone {
+            for doc_id in doc_ids {
+                table.delete(doc_id);
+            }
+            removed += 1;
+        }
+    }
+
+    if run_options.progress_mode != ProgressMode::Json {
+        let mut out = BufWriter::new(io::stdout().lock());
+        writeln!(out, "{} files new or changed, {removed} removed", changed.len())?;
+        out.flush()?;
+    }
+    if removed > 0 || touched > 0 {
+        publish(&paths, &manifest.segments, &table)?;
+    }
+    if changed.is_empty() {
+        return Ok(());
+    }
+    run(changed, paths, options, RunOptions { add: true, ..run_options }, interrupt)
+}
+
+/// How long to wait for more changes, after a change, before updating the
+/// index. Saving a file, checking out a branch, or running a build tends to
+/// change many files at once; it's better to index them all in one go.
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// How often to check for Ctrl-C while waiting for changes.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Build an index of the files and directories named `filenames`, or bring
+/// the existing one up to date, then keep it up to date as they change, until
+/// the user hits Ctrl-C.
+///
+/// Each batch of changes is handled like `--update`: changed files are
+/// indexed into new segments, which are merged into the index's stacks.
+/// Errors while updating, such as a file that vanished while it was being
+/// read, are reported, and the next change tries again.
+pub fn run_watch(
+    filenames: Vec<String>,
+    paths: IndexPaths,
+    options: IndexOptions,
+    run_options: RunOptions,
+    interrupt: &Cancel,
+) -> Result<(), Error> {
+    // Start watching before the first build, so that nothing that changes
+    // during it is missed. A directory is watched along with everything under
+    // it; a file named on its own is watched through its directory.
+    let mut watcher = Watcher::new()?;
+    for filename in &filenames {
+        let path = Path::new(filename);
+        match path.parent() {
+            _ if path.is_dir() => watcher.add_tree(path)?,
+            Some(parent) if parent != Path::new("") => watcher.add(parent)?,
+            _ => watcher.add(Path::new("."))?,
+        }
+    }
+
+    if Manifest::load(&paths)?.is_some() {
+        run_update(filenames.clone(), paths.clone(), options.clone(), run_options.clone(), interrupt)?;
+    } else {
+        let documents = expand_filename_arguments(filenames.clone(), options.mode, &paths)?;
+        run(documents, paths.clone(), options.clone(), run_options.clone(), interrupt)?;
+    }
+
+    // Writing the index is a change too, if it's kept among the documents.
+    // Those events are ignored, or the watcher would never settle down.
+    let ignore = |path: &Path| paths.owns(path);
+    loop {
+        if !watcher.wait(WATCH_POLL_INTERVAL, ignore)? {
+            if interrupt.is_cancelled() {
+                return Ok(());
+            }
+            continue;
+        }
+        while watcher.wait(WATCH_SETTLE_TIME, ignore)? {}
+        if interrupt.is_cancelled() {
+            return Ok(());
+        }
+
+        let result = run_update(
+            filenames.clone(),
+            paths.clone(),
+            options.clone(),
+            run_options.clone(),
+            interrupt,
+        );
+        match result {
+            Err(Error::Interrupted) => return Err(Error::Interrupted),
+            Err(err) => eprintln!("error: {err}"),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Delete the documents that came from the files named `filenames` from the
+/// index named by `paths`, by publishing a new generation of it, with the same
+/// segments, and with those documents marked as deleted.
+pub fn run_delete(filenames: Vec<String>, paths: &IndexPaths) -> Result<(), Error> {
+    let manifest = Manifest::load_existing(paths)?;
+    let mut table = manifest.read_table(paths)?;
+    let mut count = 0;
+    for doc_id in table.ids_from_any(&filenames) {
+        if !table.is_deleted(doc_id) {
+            table.delete(doc_id);
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Err(Error::Usage(
+            "none of the named files have any documents in the index".to_string(),
+        ));
+    }
+    publish(paths, &manifest.segments, &table)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    writeln!(out, "deleted {count} documents")?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::memory::DEFAULT_MEMORY_LIMIT;
+    use crate::paths::DEFAULT_INDEX_NAME;
+    use crate::space::SpaceCheck;
+    use crate::tests::{build, options, search_all, test_dir, write_documents};
+
+    #[test]
+    fn update_reindexes_modified_and_removed_files() {
+        let dir = test_dir("update");
+        let input = dir.join("input");
+        fs::create_dir(&input).unwrap();
+        let documents = write_documents(&input, 3);
+        let output = dir.join("index");
+        let options = options(2, DEFAULT_MEMORY_LIMIT);
+        build(&documents, &output, false, &options, false);
+
+        // doc001 is rewritten, and doc002 goes away.
+        fs::write(&documents[1], "rewritten with something else entirely").unwrap();
+        fs::remove_file(&documents[2]).unwrap();
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output.clone());
+        let run_options = RunOptions {
+            single_threaded: false,
+            add: false,
+            resume: false,
+            progress_mode: ProgressMode::Quiet,
+            space_check: SpaceCheck::Off,
+        };
+        let filenames = vec![input.display().to_string()];
+        run_update(filenames, paths.clone(), options, run_options, &Cancel::new()).unwrap();
+
+        let name = |path: &PathBuf| path.display().to_string();
+        let found = search_all(&output, &["common", "1", "2", "rewritten"]);
+        let docs = |i: usize| found[i].iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+        assert_eq!(docs(0), vec![name(&documents[0])]);
+        assert!(docs(1).is_empty());
+        assert!(docs(2).is_empty());
+        assert_eq!(docs(3), vec![name(&documents[1])]);
+
+        // The old copy of doc001 is still in the table, but tombstoned.
+        let table = Manifest::load(&paths).unwrap().unwrap().read_table(&paths).unwrap();
+        let deleted: Vec<usize> = (0..table.len()).filter(|&doc_id| table.is_deleted(doc_id)).collect();
+        assert_eq!(deleted, vec![1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_finds_files_in_subdirectories() {
+        let dir = test_dir("update-nested");
+        let input = dir.join("input");
+        let nested = input.join("src").join("lib");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(input.join("top.txt"), "top").unwrap();
+        fs::write(nested.join("deep.txt"), "deep").unwrap();
+        fs::write(nested.join("gone.txt"), "gone").unwrap();
+        let output = dir.join("index");
+        let paths = IndexPaths::new(output.clone(), DEFAULT_INDEX_NAME.to_string(), output.clone());
+        let options = options(2, DEFAULT_MEMORY_LIMIT);
+        let filenames = vec![input.display().to_string()];
+        let documents = expand_filename_arguments(filenames.clone(), InputMode::Files, &paths).unwrap();
+        assert_eq!(documents.len(), 3);
+        build(&documents, &output, false, &options, false);
+
+        fs::write(nested.join("deep.txt"), "deeper").unwrap();
+        fs::remove_file(nested.join("gone.txt")).unwrap();
+        let run_options = RunOptions {
+            single_threaded: false,
+            add: false,
+            resume: false,
+            progress_mode: ProgressMode::Quiet,
+            space_check: SpaceCheck::Off,
+        };
+        run_update(filenames, paths, options, run_options, &Cancel::new()).unwrap();
+
+        let found = search_all(&output, &["top", "deep", "deeper", "gone"]);
+        let count = |i: usize| found[i].len();
+        assert_eq!((count(0), count(1), count(2), count(3)), (1, 0, 1, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
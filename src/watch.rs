@@ -0,0 +1,305 @@
+//! Watching directories for changes, using Linux's inotify.
+//!
+//! The watcher only says *that* something changed, not what. Working out what
+//! to do about it is left to the same comparison of files with their stamps
+//! that `--update` does, which is cheap, and which can't be thrown off by
+//! events that arrive out of order, or that the kernel drops because too many
+//! happened at once.
+//!
+//! inotify watches one directory at a time, so to watch a tree, the watcher
+//! watches every directory in it, and each new directory as it appears. If the
+//! kernel drops events, the whole tree is walked again, to pick up any
+//! directories whose events were lost.
+//!
+//! There's no watcher for other systems yet. There, `Watcher::new` fails, so
+//! `fingertips watch` stops with an error before doing anything.
+
+#[cfg(target_os = "linux")]
+pub use inotify::Watcher;
+
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::Watcher;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::{HashMap, HashSet};
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// The events we care about: files and directories being written,
+    /// created, deleted, or renamed, and the watched directory itself going
+    /// away.
+    const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    /// Size of the fixed part of a `struct inotify_event`.
+    const EVENT_HEADER_SIZE: usize = 16;
+
+    /// A set of directories being watched.
+    pub struct Watcher {
+        inotify: File,
+
+        /// The directory each watch descriptor is for, to turn the filenames in
+        /// events into paths.
+        dirs: HashMap<i32, PathBuf>,
+
+        /// The watch descriptors of the directories that were asked for, as
+        /// opposed to those found under them. There's no carrying on if one of
+        /// these goes away.
+        roots: HashSet<i32>,
+
+        /// The directories watched along with everything under them.
+        trees: Vec<PathBuf>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Watcher> {
+            // SAFETY: `inotify_init1` takes no pointers.
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a file descriptor we just opened, and nothing else
+            // owns it.
+            let inotify = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            Ok(Watcher {
+                inotify,
+                dirs: HashMap::new(),
+                roots: HashSet::new(),
+                trees: vec![],
+            })
+        }
+
+        /// Start watching the directory `dir`, but not its subdirectories.
+        pub fn add(&mut self, dir: &Path) -> io::Result<()> {
+            let wd = self.watch(dir)?;
+            self.roots.insert(wd);
+            Ok(())
+        }
+
+        /// Start watching the directory `dir`, its subdirectories, and any
+        /// directories created under it later.
+        pub fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+            let wd = self.watch(dir)?;
+            self.roots.insert(wd);
+            self.trees.push(dir.to_path_buf());
+            self.watch_subdirectories(dir)
+        }
+
+        /// Watch the directories under `dir`, all the way down. Symbolic links
+        /// aren't followed. Directories that are deleted before they can be
+        /// watched are skipped.
+        fn watch_subdirectories(&mut self, dir: &Path) -> io::Result<()> {
+            let entries = match dir.read_dir() {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(io::Error::new(err.kind(), format!("{}: {err}", dir.display()))),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    self.watch_new(&entry.path())?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Watch the directory `dir`, found in one of the trees, and the
+        /// directories under it.
+        fn watch_new(&mut self, dir: &Path) -> io::Result<()> {
+            match self.watch(dir) {
+                Ok(_) => self.watch_subdirectories(dir),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Add a watch for `dir`, returning its watch descriptor. Watching a
+        /// directory that's already watched returns the same descriptor again.
+        fn watch(&mut self, dir: &Path) -> io::Result<i32> {
+            let path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+            // SAFETY: `path` is a valid C string.
+            let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(err.kind(), format!("can't watch {}: {err}", dir.display())));
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+            Ok(wd)
+        }
+
+        /// Wait up to `timeout` for something to change. Returns true if anything
+        /// did, after reading all the events that are waiting. Changes to files
+        /// for which `ignore` returns true don't count; if those are all there
+        /// are, this returns false without waiting any longer.
+        pub fn wait(&mut self, timeout: Duration, ignore: impl Fn(&Path) -> bool) -> io::Result<bool> {
+            let mut pollfd = libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            // SAFETY: `pollfd` is a valid array of one `pollfd`.
+            let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                // A signal, like Ctrl-C, is the caller's business; to us it's
+                // just a timeout.
+                return if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+            }
+            if ready == 0 {
+                return Ok(false);
+            }
+            self.read_events(ignore)
+        }
+
+        /// Read all the events waiting to be read, and return true if any of
+        /// them is about a file that isn't ignored. New directories in the
+        /// trees are watched as they're found. It's an error if one of the
+        /// directories that were asked for was deleted or moved, since there's
+        /// nothing left to watch.
+        ///
+        /// Files can be written to a new directory before it's watched. Their
+        /// events are lost, but the new directory itself counts as a change,
+        /// and by the time the caller looks for what changed, they're there.
+        fn read_events(&mut self, ignore: impl Fn(&Path) -> bool) -> io::Result<bool> {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut changed = false;
+            loop {
+                let n = match self.inotify.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                    Err(err) => return Err(err),
+                };
+                let mut events = &buf[..n];
+                while events.len() >= EVENT_HEADER_SIZE {
+                    let wd = i32::from_ne_bytes(events[0..4].try_into().unwrap());
+                    let mask = u32::from_ne_bytes(events[4..8].try_into().unwrap());
+                    let len = u32::from_ne_bytes(events[12..16].try_into().unwrap()) as usize;
+                    if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 && self.roots.contains(&wd) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "a watched directory was deleted or moved",
+                        ));
+                    }
+                    if mask & libc::IN_IGNORED != 0 {
+                        // The watch is gone, along with its directory.
+                        self.dirs.remove(&wd);
+                    }
+                    if mask & libc::IN_Q_OVERFLOW != 0 {
+                        self.rescan()?;
+                    }
+                    // The name is padded with NULs. Events without one, like a
+                    // dropped-events warning, always count.
+                    let end = (EVENT_HEADER_SIZE + len).min(events.len());
+                    let name = &events[EVENT_HEADER_SIZE..end];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    let path = self.dirs.get(&wd).filter(|_| !name.is_empty()).map(|dir| dir.join(OsStr::from_bytes(name)));
+                    events = &events[end..];
+                    let Some(path) = path else {
+                        changed = true;
+                        continue;
+                    };
+                    if mask & libc::IN_ISDIR != 0
+                        && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+                        && self.trees.iter().any(|tree| path.starts_with(tree))
+                    {
+                        self.watch_new(&path)?;
+                    }
+                    changed |= !ignore(&path);
+                }
+            }
+        }
+
+        /// Walk the trees again, watching any directories that aren't watched
+        /// yet, after the kernel dropped events that might have been about
+        /// new ones.
+        fn rescan(&mut self) -> io::Result<()> {
+            for tree in self.trees.clone() {
+                self.watch_subdirectories(&tree)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::fs;
+
+        use super::*;
+        use crate::tests::test_dir;
+
+        #[test]
+        fn watches_nested_and_new_directories() {
+            let dir = test_dir("watch-tree");
+            let nested = dir.join("a").join("b");
+            fs::create_dir_all(&nested).unwrap();
+            let mut watcher = Watcher::new().unwrap();
+            watcher.add_tree(&dir).unwrap();
+            let wait = |watcher: &mut Watcher| watcher.wait(Duration::from_secs(5), |_| false).unwrap();
+            let settle = |watcher: &mut Watcher| while watcher.wait(Duration::from_millis(50), |_| false).unwrap() {};
+
+            fs::write(nested.join("notes.txt"), "changed").unwrap();
+            assert!(wait(&mut watcher));
+            settle(&mut watcher);
+
+            // A directory made after watching started is watched too, and so
+            // is one made inside that.
+            let new = dir.join("c").join("d");
+            fs::create_dir_all(&new).unwrap();
+            assert!(wait(&mut watcher));
+            settle(&mut watcher);
+            fs::write(new.join("notes.txt"), "new").unwrap();
+            assert!(wait(&mut watcher));
+            settle(&mut watcher);
+
+            // Deleting a subdirectory is a change, not the end of watching.
+            fs::remove_dir_all(dir.join("a")).unwrap();
+            assert!(wait(&mut watcher));
+            settle(&mut watcher);
+            fs::write(new.join("notes.txt"), "again").unwrap();
+            assert!(wait(&mut watcher));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::error::Error;
+
+    /// A stand-in for the inotify watcher, which can't be made.
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn new() -> io::Result<Watcher> {
+            Err(Error::Usage("fingertips watch only works on Linux".to_string()).into())
+        }
+
+        pub fn add(&mut self, _dir: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn add_tree(&mut self, _dir: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn wait(&mut self, _timeout: Duration, _ignore: impl Fn(&Path) -> bool) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+}
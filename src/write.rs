@@ -2,15 +2,19 @@ use std::fs::File;
 use std::io::{self, BufWriter, SeekFrom};
 use std::io::prelude::*;
 use std::path::PathBuf;
+use crate::error::{Context, Section};
 use crate::index::InMemoryIndex;
 use crate::tmp::TmpDir;
+use crate::progress::{Event, Progress};
 use byteorder::{LittleEndian, WriteBytesExt};
 
 /// Writer for saving an index to a binary file.
 ///
-/// The first 8 bytes of the index file contain the offset of the table of
-/// contents, in bytes. Then come the main entries, all stored back-to-back
-/// with no particular metadata. Lastly there's the table of contents.
+/// The index file starts with a 16-byte header: the magic number `MAGIC`, the
+/// format version as a little-endian u32, and the offset of the table of
+/// contents, in bytes, as a little-endian u64. Then come the main entries, all
+/// stored back-to-back with no particular metadata. Lastly there's the table
+/// of contents.
 pub struct IndexFileWriter {
     /// The number of bytes written so far.
     offset: u64,
@@ -18,27 +22,84 @@ pub struct IndexFileWriter {
     /// The open file we're writing to.
     writer: BufWriter<File>,
 
+    /// Its name, for error messages.
+    filename: PathBuf,
+
     /// The table of contents for this file.
     contents_buf: Vec<u8>,
 }
 
+/// The first bytes of every index file, so that other files aren't mistaken
+/// for one.
+pub const MAGIC: &[u8; 4] = b"FTix";
+
+/// The version of the index file format. Version 1, written before hits
+/// included a count, had no magic number or version, so those files are
+/// rejected as not being index files at all.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Where in the header the offset of the table of contents is.
+pub const CONTENTS_OFFSET_POSITION: u64 = 8;
+
+/// Size of the header: the magic number, the version, and the offset of the
+/// table of contents.
+pub const HEADER_SIZE: u64 = 16;
+
 impl IndexFileWriter {
-    pub fn new(mut f: BufWriter<File>) -> io::Result<IndexFileWriter> {
-        const HEADER_SIZE: u64 = 8;
-        f.write_u64::<LittleEndian>(0)?;
+    /// Start writing an index file to `f`, which is open to `filename`.
+    pub fn new(filename: PathBuf, mut f: BufWriter<File>) -> io::Result<IndexFileWriter> {
+        f.write_all(MAGIC)
+            .and_then(|_| f.write_u32::<LittleEndian>(FORMAT_VERSION))
+            .and_then(|_| f.write_u64::<LittleEndian>(0))
+            .in_index_file(&filename, Section::Header, 0)?;
         Ok(IndexFileWriter {
             offset: HEADER_SIZE,
             writer: f,
+            filename,
             contents_buf: vec![],
         })
     }
 
+    /// The number of bytes written so far. This is the offset where the next
+    /// index data will go.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
     pub fn write_main(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.writer.write_all(buf)?;
+        self.writer
+            .write_all(buf)
+            .in_index_file(&self.filename, Section::Postings, self.offset)?;
         self.offset += buf.len() as u64;
         Ok(())
     }
 
+    /// Copy exactly `nbytes` bytes of index data from `reader`.
+    ///
+    /// Errors writing say they're about this file; errors reading are left
+    /// for the caller to say which file they're about.
+    pub fn copy_main_from<R: Read>(&mut self, mut reader: R, nbytes: u64) -> io::Result<()> {
+        let mut buf = [0; 8 * 1024];
+        let mut remaining = nbytes;
+        while remaining > 0 {
+            let len = remaining.min(buf.len() as u64) as usize;
+            let n = match reader.read(&mut buf[..len]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "index file ended in the middle of an entry",
+                    ));
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            self.write_main(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
     pub fn write_contents_entry(&mut self, term: String, df: u32, offset: u64, nbytes: u64) {
         self.contents_buf.write_u64::<LittleEndian>(offset).unwrap();
         self.contents_buf.write_u64::<LittleEndian>(nbytes).unwrap();
@@ -48,20 +109,28 @@ impl IndexFileWriter {
         self.contents_buf.extend(bytes);
     }
 
-    /// Finish writing the index file and close it.
-    pub fn finish(mut self) -> io::Result<()> {
+    /// Finish writing the index file and close it. Returns the total size of
+    /// the file, in bytes.
+    pub fn finish(mut self) -> io::Result<u64> {
         let contents_start = self.offset;
-        self.writer.write_all(&self.contents_buf)?;
-        println!("{contents_start} bytes main, {} bytes total", contents_start + self.contents_buf.len() as u64);
-        self.writer.seek(SeekFrom::Start(0))?;
-        self.writer.write_u64::<LittleEndian>(contents_start)?;
-        Ok(())
+        self.writer
+            .write_all(&self.contents_buf)
+            .in_index_file(&self.filename, Section::Contents, contents_start)?;
+        self.writer.seek(SeekFrom::Start(CONTENTS_OFFSET_POSITION))
+            .and_then(|_| self.writer.write_u64::<LittleEndian>(contents_start))
+            .and_then(|_| self.writer.flush())
+            .in_index_file(&self.filename, Section::Header, CONTENTS_OFFSET_POSITION)?;
+        Ok(contents_start + self.contents_buf.len() as u64)
     }
 }
 
-pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> io::Result<PathBuf> {
+pub fn write_index_to_tmp_file(
+    index: InMemoryIndex,
+    tmp_dir: &TmpDir,
+    progress: &Progress,
+) -> io::Result<PathBuf> {
     let (filename, f) = tmp_dir.create()?;
-    let mut writer = IndexFileWriter::new(f)?;
+    let mut writer = IndexFileWriter::new(filename.clone(), f)?;
 
     // The merge algorithm requires the entries within each file to be sorted by term.
     // Sort before writing anything.
@@ -78,7 +147,8 @@ pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> io
         writer.write_contents_entry(term, df, start, stop - start);
     }
 
-    writer.finish()?;
-    println!("wrote file {filename:?}");
+    let bytes = writer.finish()?;
+    tmp_dir.add_usage(bytes);
+    progress.event(Event::TmpFileWritten { path: &filename, bytes });
     Ok(filename)
 }